@@ -16,6 +16,12 @@ window.getCaretClickPosition = function (element_id = '') {
     return 0;
 }
 
+// Decodes base64 text from Rust as UTF-8; `atob` alone would mangle anything outside Latin-1
+window.decodeBase64 = function (text_b64 = '') {
+    const bytes = Uint8Array.from(atob(text_b64), c => c.charCodeAt(0));
+    return new TextDecoder().decode(bytes);
+}
+
 window.getElementText = function (element_id = '') {
     // Get the element by ID
     const element = document.getElementById(element_id);
@@ -107,10 +113,15 @@ window.focusElement = function (element_id = '') {
     }
   }
 
-window.focusElementAndSetCaret = function (elementId, caretPos) {
+window.focusElementAndSetCaret = function (elementId, caretPos, attempt = 0) {
     const el = document.getElementById(elementId);
     if (!el) {
-      console.warn(`Element with id '${elementId}' not found.`);
+      // A row scrolled into view may not be rendered yet; wait a few frames for it
+      if (attempt < 10) {
+        requestAnimationFrame(() => window.focusElementAndSetCaret(elementId, caretPos, attempt + 1));
+      } else {
+        console.warn(`Element with id '${elementId}' not found.`);
+      }
       return;
     }
 
@@ -155,196 +166,6 @@ window.focusElementAndSetCaret = function (elementId, caretPos) {
     }
   };
 
-window.deleteRow = function (row_id = '', newRowsData = null) {
-    const row = document.getElementById(row_id);
-    if (row) {
-        // Extract row index from row_id (format: textrow-N)
-        const deletedRowIndex = parseInt(row_id.split('-')[1]);
-        const container = document.getElementById('container');
-        
-        // Remove the row
-        row.remove();
-        
-        if (container) {
-            // Renumber all subsequent rows and their cells
-            const allRows = container.querySelectorAll('[id^="textrow-"]');
-            for (let remainingRow of allRows) {
-                const currentIndex = parseInt(remainingRow.id.split('-')[1]);
-                if (currentIndex > deletedRowIndex) {
-                    const newRowId = `textrow-${currentIndex - 1}`;
-                    remainingRow.id = newRowId;
-                    
-                    // Update all cells in this row
-                    const cells = remainingRow.querySelectorAll('[id^="textarea-"]');
-                    for (let cell of cells) {
-                        const parts = cell.id.split('-');
-                        if (parts.length === 3) {
-                            const oldCellIndex = parseInt(parts[1]);
-                            if (oldCellIndex === currentIndex) {
-                                cell.id = `textarea-${currentIndex - 1}-${parts[2]}`;
-                                
-                                // Update cell content if new data is provided
-                                if (newRowsData && newRowsData[currentIndex - 1 - deletedRowIndex]) {
-                                    const rowData = newRowsData[currentIndex - 1 - deletedRowIndex];
-                                    const colIndex = parseInt(parts[2]);
-                                    if (rowData[colIndex] !== undefined) {
-                                        cell.textContent = rowData[colIndex];
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        
-        console.log(`Deleted row: ${row_id}`);
-    } else {
-        console.warn(`Row with id '${row_id}' not found`);
-    }
-};
-
-window.createRow = function (row_id = '', insertAfter = null) {
-  const container = document.getElementById('container');
-  if (!container) {
-      console.warn('Container not found');
-      return null;
-  }
-
-  // Extract row index from row_id (format: textrow-N)
-  const newRowIndex = row_id ? parseInt(row_id.split('-')[1]) : 0;
-
-  // Check for existing row with the same ID and remove it
-  const existingRow = document.getElementById(row_id);
-  if (existingRow) {
-      existingRow.remove();
-      console.log(`Removed existing row: ${row_id}`);
-  }
-
-  // Create new row
-  const newRow = document.createElement('div');
-  newRow.id = row_id;
-  newRow.style.cssText = 'display: flex; flex-direction: row; gap: 0; flex-wrap: wrap; font-size: 0;';
-
-  // Insert the new row
-  if (insertAfter) {
-      const afterElement = document.getElementById(insertAfter);
-      if (afterElement && afterElement.nextSibling) {
-          container.insertBefore(newRow, afterElement.nextSibling);
-      } else {
-          container.appendChild(newRow);
-      }
-  } else {
-      container.appendChild(newRow);
-  }
-
-  // Renumber all rows to ensure consecutive IDs
-  const allRows = Array.from(container.querySelectorAll('[id^="textrow-"]')).sort((a, b) => {
-      const aIndex = parseInt(a.id.split('-')[1]) || 0;
-      const bIndex = parseInt(b.id.split('-')[1]) || 0;
-      return aIndex - bIndex;
-  });
-
-  // Update row IDs and their cells
-  for (let i = 0; i < allRows.length; i++) {
-      const row = allRows[i];
-      const currentIndex = parseInt(row.id.split('-')[1]) || 0;
-      const newRowId = `textrow-${i}`;
-      
-      if (row.id !== newRowId) {
-          row.id = newRowId;
-          // Update all cells in this row
-          const cells = row.querySelectorAll('[id^="textarea-"]');
-          for (let cell of cells) {
-              const parts = cell.id.split('-');
-              if (parts.length === 3) {
-                  cell.id = `textarea-${i}-${parts[2]}`;
-              }
-          }
-      }
-  }
-
-  // Remove empty rows (except the new row if it's empty)
-  for (let row of allRows) {
-      if (row.id !== row_id && row.children.length === 0) {
-          row.remove();
-          console.log(`Removed empty row: ${row.id}`);
-      }
-  }
-
-  console.log(`Created row: ${row_id}`);
-  return newRow;
-};
-
-window.createRow = function (row_id = '', insertAfter = null) {
-  const newRow = document.createElement('div');
-  newRow.id = row_id;
-  newRow.style.cssText = 'display: flex; flex-direction: row; gap: 0; flex-wrap: wrap; font-size: 0;';
-  
-  const container = document.getElementById('container');
-  if (container) {
-      // Extract row index from row_id (format: textrow-N)
-      const newRowIndex = parseInt(row_id.split('-')[1]);
-      
-      if (insertAfter) {
-          const afterElement = document.getElementById(insertAfter);
-          if (afterElement && afterElement.nextSibling) {
-              container.insertBefore(newRow, afterElement.nextSibling);
-          } else {
-              container.appendChild(newRow);
-          }
-      } else {
-          container.appendChild(newRow);
-      }
-      
-      // Renumber all subsequent rows and their cells
-      const allRows = container.querySelectorAll('[id^="textrow-"]');
-      for (let row of allRows) {
-          const currentIndex = parseInt(row.id.split('-')[1]);
-          if (currentIndex > newRowIndex) {
-              const oldRowId = row.id;
-              const newRowId = `textrow-${currentIndex + 1}`;
-              row.id = newRowId;
-              
-              // Update all cells in this row
-              const cells = row.querySelectorAll('[id^="textarea-"]');
-              for (let cell of cells) {
-                  const parts = cell.id.split('-');
-                  if (parts.length === 3) {
-                      const oldCellIndex = parseInt(parts[1]);
-                      if (oldCellIndex === currentIndex) {
-                          cell.id = `textarea-${currentIndex + 1}-${parts[2]}`;
-                      }
-                  }
-              }
-          }
-      }
-      
-      console.log(`Created row: ${row_id}`);
-  } else {
-      console.warn('Container not found');
-  }
-  return newRow;
-};
-
-window.createCell = function (element_id = '', row_id = '', text_content = '', cellStyle = '') {
-    const cell = document.createElement('div');
-    cell.id = element_id;
-    cell.contentEditable = true;
-    cell.className = 'base-paragraph';
-    cell.style.cssText = cellStyle;
-    cell.textContent = text_content;
-    
-    const row = document.getElementById(row_id);
-    if (row) {
-        row.appendChild(cell);
-        console.log(`Created cell: ${element_id} in row: ${row_id}`);
-    } else {
-        console.warn(`Row with id '${row_id}' not found`);
-    }
-    return cell;
-};
-
 window.updateRow = function (row_id = '', rowData = []) {
     const row = document.getElementById(row_id);
     if (!row) {
@@ -367,4 +188,71 @@ window.updateRow = function (row_id = '', rowData = []) {
     }
     
     console.log(`Updated row: ${row_id} with ${rowData.length} columns`);
-};
\ No newline at end of file
+};
+// Returns the DOM selection as [row id, column, offset] cell positions, or null when nothing is selected
+window.getSelectionRange = function () {
+    const sel = window.getSelection();
+    if (!sel || sel.rangeCount === 0 || sel.isCollapsed) {
+        return null;
+    }
+
+    const cellPosition = function (node, offset) {
+        let el = node.nodeType === Node.TEXT_NODE ? node.parentElement : node;
+        while (el && !(el.id && el.id.startsWith('textarea-'))) {
+            el = el.parentElement;
+        }
+        if (!el) return null;
+        const parts = el.id.split('-');
+        return [parseInt(parts[1]), parseInt(parts[2]), offset];
+    };
+
+    const anchor = cellPosition(sel.anchorNode, sel.anchorOffset);
+    const focus = cellPosition(sel.focusNode, sel.focusOffset);
+    if (!anchor || !focus) {
+        return null;
+    }
+    return [anchor, focus];
+};
+
+// Shows a selection from one cell position to another, e.g. for Shift+Arrow
+window.selectCellRange = function (anchorId, anchorOffset, focusId, focusOffset) {
+    const textPosition = function (element_id, offset) {
+        const el = document.getElementById(element_id);
+        if (!el) return null;
+        let node = el.firstChild;
+        while (node && node.nodeType !== Node.TEXT_NODE) {
+            node = node.firstChild;
+        }
+        if (!node) {
+            return [el, 0];
+        }
+        return [node, Math.min(offset, node.length || 0)];
+    };
+
+    const anchor = textPosition(anchorId, anchorOffset);
+    const focus = textPosition(focusId, focusOffset);
+    if (!anchor || !focus) {
+        console.warn('Could not select range:', anchorId, focusId);
+        return;
+    }
+    window.getSelection().setBaseAndExtent(anchor[0], anchor[1], focus[0], focus[1]);
+};
+
+// How many rows fit in the window, used as the PageUp / PageDown step
+window.visibleRowCount = function () {
+    const row = document.querySelector('[id^="textrow-"]');
+    const rowHeight = row ? row.getBoundingClientRect().height : 0;
+    if (!rowHeight) {
+        return 20;
+    }
+    return Math.max(1, Math.floor(window.innerHeight / rowHeight));
+};
+
+// The scroll position and height of a scroll container, for virtualized rendering
+window.scrollMetrics = function (element_id = '') {
+    const el = document.getElementById(element_id);
+    if (!el) {
+        return [0, window.innerHeight];
+    }
+    return [el.scrollTop, el.clientHeight];
+};
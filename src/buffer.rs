@@ -0,0 +1,402 @@
+// src/buffer.rs
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+
+use crate::syntax::text::SourceLines;
+
+/// Marks where one cell of a row ends and the next begins. A control
+/// character that typing never produces; inside cell text it is escaped, so
+/// it can only ever be a boundary.
+const CELL_SEPARATOR: char = '\u{1f}';
+
+/// Ends the row id at the start of each line.
+const ID_SEPARATOR: char = '\u{1e}';
+
+/// Starts an escape in cell text: a separator, or this character itself, is
+/// stored as `ESCAPE` followed by its code from `ESCAPED`.
+const ESCAPE: char = '\u{1b}';
+
+const ESCAPED: [(char, char); 3] = [(ESCAPE, 'e'), (ID_SEPARATOR, 'r'), (CELL_SEPARATOR, 'u')];
+
+/// Identifies a row for as long as it exists, wherever rows are inserted or
+/// removed around it. The webview's element ids are made from it, so a
+/// structural edit leaves the elements of the other rows alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RowId(u64);
+
+impl RowId {
+    /// A new id, unique across every `Document`, so the view's copy of the
+    /// buffer can never hand out an id the editor's copy uses.
+    fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The id of the row's element.
+    pub fn row_element_id(self) -> String {
+        format!("textrow-{}", self.0)
+    }
+
+    /// The id of the element of cell `index_j` in the row.
+    pub fn cell_element_id(self, index_j: usize) -> String {
+        format!("textarea-{}-{}", self.0, index_j)
+    }
+}
+
+impl fmt::Display for RowId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The document buffer: a rope with one line per row. A line starts with the
+/// row's `RowId`, then lays out the row's cells with `CELL_SEPARATOR` between them.
+///
+/// Rows are found, inserted and removed in O(log n), so an edit at the top of
+/// a long document costs the same as one at the bottom, and cloning the
+/// buffer (for the view's copy of it) shares the rope instead of copying it.
+/// Rows and cells are decoded from the line when they are read.
+///
+/// The document also keeps the range of rows edited since `take_edited` was
+/// last called, so the parse can be brought up to date from that alone.
+#[derive(Debug, Clone, Default)]
+pub struct Document {
+    rope: Rope,
+    edited: Option<Range<usize>>,
+    /// The position of each row id, built by the first `row_index` after rows
+    /// were inserted or removed. Clones share it until either side changes.
+    indices: Arc<OnceLock<HashMap<RowId, usize>>>,
+}
+
+impl Document {
+    pub fn from_rows<I, R>(rows: I) -> Self
+    where
+        I: IntoIterator<Item = R>,
+        R: AsRef<[String]>,
+    {
+        let mut text = String::new();
+        for cells in rows {
+            encode_row(&mut text, RowId::next(), cells.as_ref());
+        }
+        let rope = Rope::from_str(&text);
+        let edited = Some(0..rope.len_lines() - 1);
+        Self { rope, edited, indices: Arc::default() }
+    }
+
+    /// The number of rows.
+    pub fn len(&self) -> usize {
+        // The rope ends on a line break, which starts an empty line of its own
+        self.rope.len_lines() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The cells of row `index_i`. A row always has at least one cell.
+    pub fn row(&self, index_i: usize) -> Option<Vec<String>> {
+        self.line_with_separators(index_i)
+            .map(|line| line.split(CELL_SEPARATOR).map(unescape).collect())
+    }
+
+    /// The text of a cell, if the row and the cell exist.
+    pub fn cell(&self, index_i: usize, index_j: usize) -> Option<String> {
+        self.line_with_separators(index_i)?
+            .split(CELL_SEPARATOR)
+            .nth(index_j)
+            .map(unescape)
+    }
+
+    pub fn row_id(&self, index_i: usize) -> Option<RowId> {
+        if index_i >= self.len() {
+            return None;
+        }
+        let digits: String = self.rope.line(index_i).chars().take_while(|&c| c != ID_SEPARATOR).collect();
+        digits.parse().ok().map(RowId)
+    }
+
+    /// The row with the id `id`, if it is still in the document. The first
+    /// lookup after rows were inserted or removed maps every id again; edits
+    /// inside rows keep the map.
+    pub fn row_index(&self, id: RowId) -> Option<usize> {
+        self.indices
+            .get_or_init(|| (0..self.len()).filter_map(|index_i| Some((self.row_id(index_i)?, index_i))).collect())
+            .get(&id)
+            .copied()
+    }
+
+    /// The number of cells in row `index_i`, or 0 past the end of the document.
+    pub fn row_len(&self, index_i: usize) -> usize {
+        if index_i >= self.len() {
+            return 0;
+        }
+        self.rope.line(index_i).chars().filter(|&c| c == CELL_SEPARATOR).count() + 1
+    }
+
+    /// The Markdown source of row `index_i`: its cells joined together.
+    pub fn line(&self, index_i: usize) -> Option<String> {
+        self.line_with_separators(index_i)
+            .map(|line| unescape(&line.replace(CELL_SEPARATOR, "")))
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = Vec<String>> + '_ {
+        (0..self.len()).filter_map(|index_i| self.row(index_i))
+    }
+
+    pub fn to_rows(&self) -> Vec<Vec<String>> {
+        self.rows().collect()
+    }
+
+    /// Inserts a new row before row `index_i`, or at the end past it, and returns its id.
+    pub fn insert_row(&mut self, index_i: usize, cells: &[String]) -> RowId {
+        let id = RowId::next();
+        self.insert_row_with_id(index_i, id, cells);
+        id
+    }
+
+    /// Inserts a row that keeps the id `id`: one put back by undo, or the
+    /// view's copy of a row the editor inserted.
+    pub fn insert_row_with_id(&mut self, index_i: usize, id: RowId, cells: &[String]) {
+        let index_i = index_i.min(self.len());
+        let mut text = String::new();
+        encode_row(&mut text, id, cells);
+        self.rope.insert(self.rope.line_to_char(index_i), &text);
+        self.indices = Arc::default();
+
+        // The edited rows at and after the new one move down
+        let shifted = self.edited.take().map(|edited| {
+            let shift = |row: usize| if row > index_i { row + 1 } else { row };
+            shift(edited.start)..shift(edited.end)
+        });
+        self.mark_edited(shifted, index_i..index_i + 1);
+    }
+
+    pub fn push_row(&mut self, cells: &[String]) -> RowId {
+        self.insert_row(self.len(), cells)
+    }
+
+    /// Removes row `index_i` and returns its cells.
+    pub fn remove_row(&mut self, index_i: usize) -> Option<Vec<String>> {
+        let cells = self.row(index_i)?;
+        let start = self.rope.line_to_char(index_i);
+        let end = self.rope.line_to_char(index_i + 1);
+        self.rope.remove(start..end);
+        self.indices = Arc::default();
+
+        // The edited rows after the removed one move up; the gap it leaves counts as edited
+        let shifted = self.edited.take().map(|edited| {
+            let shift = |row: usize| if row > index_i { row - 1 } else { row };
+            shift(edited.start)..shift(edited.end)
+        });
+        self.mark_edited(shifted, index_i..index_i);
+        Some(cells)
+    }
+
+    /// The rows inserted, removed or changed since the last call, as a range of
+    /// the current rows, or `None` if there were none. Where rows were only
+    /// removed, the range is empty.
+    pub fn take_edited(&mut self) -> Option<Range<usize>> {
+        self.edited.take()
+    }
+
+    fn mark_edited(&mut self, edited: Option<Range<usize>>, rows: Range<usize>) {
+        self.edited = Some(match edited {
+            Some(edited) => edited.start.min(rows.start)..edited.end.max(rows.end),
+            None => rows,
+        });
+    }
+
+    /// Replaces the cells of row `index_i`, keeping its id, and returns the old ones.
+    pub fn set_row(&mut self, index_i: usize, cells: &[String]) -> Option<Vec<String>> {
+        let id = self.row_id(index_i)?;
+        let indices = self.indices.clone();
+        let old = self.remove_row(index_i)?;
+        self.insert_row_with_id(index_i, id, cells);
+        // The row keeps its id and its place, so the other rows' positions hold
+        self.indices = indices;
+        Some(old)
+    }
+
+    /// Edits the cells of row `index_i` in place. Returns `None`, without
+    /// calling `edit`, if the row does not exist.
+    pub fn update_row<T>(&mut self, index_i: usize, edit: impl FnOnce(&mut Vec<String>) -> T) -> Option<T> {
+        let mut cells = self.row(index_i)?;
+        let result = edit(&mut cells);
+        self.set_row(index_i, &cells);
+        Some(result)
+    }
+
+    /// Sets the text of a cell, adding empty rows and cells up to it if needed.
+    pub fn set_cell(&mut self, index_i: usize, index_j: usize, text: &str) {
+        while self.len() <= index_i {
+            self.push_row(&[String::new()]);
+        }
+        self.update_row(index_i, |cells| {
+            cells.resize(cells.len().max(index_j + 1), String::new());
+            cells[index_j] = text.to_string();
+        });
+    }
+
+    /// The cells of row `index_i` as stored, separators included, without
+    /// the row id and the line break.
+    fn line_with_separators(&self, index_i: usize) -> Option<String> {
+        if index_i >= self.len() {
+            return None;
+        }
+        let mut line = String::from(self.rope.line(index_i));
+        line.pop();
+        let start = line.find(ID_SEPARATOR).map_or(0, |index| index + ID_SEPARATOR.len_utf8());
+        Some(line.split_off(start))
+    }
+}
+
+impl SourceLines for Document {
+    fn line_count(&self) -> usize {
+        self.len()
+    }
+
+    fn source_line(&self, index_i: usize) -> String {
+        self.line(index_i).unwrap_or_default()
+    }
+}
+
+/// Appends a row to `text` as a line of the rope. Separators inside a cell
+/// are escaped; a line break would split the line, and cannot be part of a
+/// row anyway, so it is dropped.
+fn encode_row(text: &mut String, id: RowId, cells: &[String]) {
+    text.push_str(&id.to_string());
+    text.push(ID_SEPARATOR);
+    for (index_j, cell) in cells.iter().enumerate() {
+        if index_j > 0 {
+            text.push(CELL_SEPARATOR);
+        }
+        for c in cell.chars() {
+            if let Some(&(_, code)) = ESCAPED.iter().find(|(escaped, _)| *escaped == c) {
+                text.push(ESCAPE);
+                text.push(code);
+            } else if c != '\n' {
+                text.push(c);
+            }
+        }
+    }
+    text.push('\n');
+}
+
+/// Cell text as stored in the rope, with its escapes undone.
+fn unescape(stored: &str) -> String {
+    let mut text = String::with_capacity(stored.len());
+    let mut chars = stored.chars();
+    while let Some(c) = chars.next() {
+        if c != ESCAPE {
+            text.push(c);
+        } else if let Some(code) = chars.next() {
+            text.extend(ESCAPED.iter().find(|(_, escaped)| *escaped == code).map(|&(c, _)| c));
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(cells: &[&str]) -> Vec<String> {
+        cells.iter().map(|text| text.to_string()).collect()
+    }
+
+    #[test]
+    fn test_rows_round_trip_through_the_rope() {
+        let rows = vec![row(&["# Title"]), row(&["say ", "**bold**", " text"]), row(&[""]), row(&["é ✓"])];
+        let document = Document::from_rows(&rows);
+
+        assert_eq!(document.len(), 4);
+        assert_eq!(document.to_rows(), rows);
+        assert_eq!(document.cell(1, 1).as_deref(), Some("**bold**"));
+        assert_eq!(document.cell(1, 3), None);
+        assert_eq!(document.row_len(1), 3);
+        assert_eq!(document.row_len(4), 0);
+        assert_eq!(document.line(1).as_deref(), Some("say **bold** text"));
+        assert_eq!(document.row(4), None);
+        assert!(Document::default().is_empty());
+    }
+
+    #[test]
+    fn test_row_edits_leave_the_other_rows() {
+        let mut document = Document::from_rows([row(&["a"]), row(&["b", "c"]), row(&["d"])]);
+
+        document.insert_row(1, &row(&["new"]));
+        assert_eq!(document.to_rows(), vec![row(&["a"]), row(&["new"]), row(&["b", "c"]), row(&["d"])]);
+
+        assert_eq!(document.remove_row(2), Some(row(&["b", "c"])));
+        assert_eq!(document.remove_row(3), None);
+        document.update_row(0, |cells| cells.push("z".to_string()));
+        document.push_row(&row(&["e"]));
+        assert_eq!(document.to_rows(), vec![row(&["a", "z"]), row(&["new"]), row(&["d"]), row(&["e"])]);
+
+        document.set_cell(5, 2, "far");
+        assert_eq!(document.row(4), Some(row(&[""])));
+        assert_eq!(document.row(5), Some(row(&["", "", "far"])));
+    }
+
+    #[test]
+    fn test_row_ids_follow_their_rows() {
+        let mut document = Document::from_rows([row(&["a"]), row(&["b"]), row(&["c"])]);
+        let (a, b, c) = (document.row_id(0).unwrap(), document.row_id(1).unwrap(), document.row_id(2).unwrap());
+        assert!(a != b && b != c);
+
+        let new = document.insert_row(1, &row(&["new"]));
+        assert_eq!(document.row_index(b), Some(2));
+        document.set_row(2, &row(&["b", "split"]));
+        assert_eq!(document.row_index(b), Some(2));
+        document.remove_row(0);
+        assert_eq!((0..3).map(|index_i| document.row_id(index_i).unwrap()).collect::<Vec<_>>(), vec![new, b, c]);
+        assert_eq!(document.row_index(c), Some(2));
+        assert_eq!(document.row_index(a), None);
+
+        document.insert_row_with_id(0, a, &row(&["a"]));
+        assert_eq!(document.row_index(a), Some(0));
+        assert_eq!(document.row_id(4), None);
+        assert_eq!(b.cell_element_id(1), format!("textarea-{}-1", b));
+    }
+
+    #[test]
+    fn test_edited_rows_are_tracked_until_taken() {
+        let mut document = Document::from_rows((0..10).map(|index| row(&[&index.to_string()])));
+        assert_eq!(document.take_edited(), Some(0..10));
+        assert_eq!(document.take_edited(), None);
+
+        document.set_row(4, &row(&["x"]));
+        document.insert_row(2, &row(&["new"]));
+        assert_eq!(document.take_edited(), Some(2..6));
+
+        // Removing rows leaves an empty range where they were
+        document.remove_row(7);
+        document.remove_row(7);
+        assert_eq!(document.take_edited(), Some(7..7));
+
+        document.remove_row(0);
+        document.update_row(5, |cells| cells.push("y".to_string()));
+        assert_eq!(document.take_edited(), Some(0..6));
+    }
+
+    #[test]
+    fn test_separators_in_cells_are_escaped() {
+        let cells = row(&["a\u{1f}b\u{1e}", "\u{1b}u\u{1b}", "\r\u{2028}"]);
+        let document = Document::from_rows([cells.clone(), row(&["next"])]);
+        assert_eq!(document.to_rows(), vec![cells, row(&["next"])]);
+        assert_eq!(document.row_len(0), 3);
+        assert_eq!(document.cell(0, 1).as_deref(), Some("\u{1b}u\u{1b}"));
+        assert_eq!(document.line(0).as_deref(), Some("a\u{1f}b\u{1e}\u{1b}u\u{1b}\r\u{2028}"));
+
+        // Line breaks cannot be part of a row
+        let document = Document::from_rows([row(&["a\nb"])]);
+        assert_eq!(document.to_rows(), vec![row(&["ab"])]);
+        assert_eq!(Document::from_rows([Vec::<String>::new()]).row(0), Some(row(&[""])));
+    }
+}
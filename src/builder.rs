@@ -1,6 +1,9 @@
 // src/builder.rs
 use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
 use dioxus::prelude::*;
+use crate::buffer::Document;
+use crate::offset::TextOffset;
 use crate::state;
 // use crate::markdown;
 
@@ -44,8 +47,8 @@ impl EditorBuilder {
     //     self.state.raw_text.join(" ")
     // }
 
-    /// Returns a reference to the raw text vector.
-    pub fn get_raw_text_vec(&self) -> &Vec<Vec<String>> {
+    /// Returns a reference to the document buffer.
+    pub fn get_raw_text_vec(&self) -> &Document {
         &self.state.raw_text
     }
 
@@ -60,7 +63,7 @@ impl EditorBuilder {
 
     pub fn get_raw_text_current(&self) -> String {
         if let Some((index_i, index_j, _)) = self.get_caret_pos() {
-            self.state.raw_text[index_i][index_j].to_string()
+            self.cell_text(index_i, index_j)
         }
         else {
             String::from("error")
@@ -87,6 +90,32 @@ impl EditorBuilder {
         self.state.caret_pos
     }
 
+    /// Converts a byte offset in a cell to the UTF-16 offset the DOM expects.
+    pub fn dom_offset(&self, index_i: usize, index_j: usize, char_pos: usize) -> usize {
+        let text = self.cell_text(index_i, index_j);
+        TextOffset::from_byte(&text, char_pos).utf16(&text)
+    }
+
+    /// Converts a UTF-16 offset reported by the DOM to a byte offset in a cell.
+    pub fn offset_from_dom(&self, index_i: usize, index_j: usize, dom_pos: usize) -> usize {
+        TextOffset::from_utf16(&self.cell_text(index_i, index_j), dom_pos).byte()
+    }
+
+    /// Replaces the whole document, e.g. after opening another file.
+    pub fn replace_state(&mut self, state: State) {
+        self.state = state;
+    }
+
+    /// Saves the document to `path`, or to the file it was opened from when `None`.
+    pub fn save_file(&mut self, path: Option<PathBuf>) -> std::io::Result<()> {
+        self.state.save(path)
+    }
+
+    /// Returns `true` if there are edits that have not been saved.
+    pub fn is_dirty(&self) -> bool {
+        self.state.dirty
+    }
+
     /// Sends a command to the UCI engine via the coroutine, if available.
     pub fn send_uci_command(&self, command: &str) {
         if let Some(tx) = &self.uci_action_tx {
@@ -96,7 +125,7 @@ impl EditorBuilder {
 
     pub fn get_row_level_caret_pos(&self, current_caret_pos: Option<(usize, usize, usize)>) -> Option<usize> {
         if let Some((index_i, index_j, char_pos)) = current_caret_pos {
-            let row = &self.state.raw_text[index_i];
+            let row = self.state.raw_text.row(index_i)?;
             // Sum lengths of all texts in columns before index_j
             let mut total_pos = 0;
             for j in 0..index_j {
@@ -110,31 +139,4 @@ impl EditorBuilder {
         }
     }
 
-    pub fn get_caret_from_row_level_pos(&self, row_level_pos: usize, index_i: usize, row: Vec<String>) -> Option<(usize, usize, usize)> {
-        // if index_i >= self.state.raw_text.len() {
-        //     return None; // Invalid row index
-        // }
-    
-        // let row = &self.state.raw_text[index_i];
-        let mut remaining_pos = row_level_pos;
-    
-        for index_j in 0..row.len() {
-            let text_len = row[index_j].len();
-            if remaining_pos < text_len {
-                // The position falls within this column
-                return Some((index_i, index_j, remaining_pos + 1));
-            }
-            remaining_pos -= text_len;
-        }
-    
-        // If the position exceeds the total length of the row, return the last column's end
-        if !row.is_empty() {
-            let last_j = row.len() - 1;
-            let last_text_len = row[last_j].len();
-            Some((index_i, last_j, last_text_len))
-        } else {
-            None // Empty row
-        }
-    }
-
-}
\ No newline at end of file
+}
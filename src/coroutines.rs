@@ -1,166 +1,100 @@
 use dioxus::prelude::*;
 use crate::builder::EditorBuilder;
-use crate::get_element_id;
-use std::time::Duration;
+use crate::offset::TextOffset;
+use futures_util::StreamExt;
 
-use base64::{engine::general_purpose, Engine as _};
 
-pub async fn measure_width_coroutine(mut rx: UnboundedReceiver<(usize, usize, String)>, editor: &mut Signal<EditorBuilder>) {
+/// Measures the cells `State::measure_text_widths` could not, i.e. text in a
+/// font we don't ship, by laying it out in a hidden span.
+pub async fn measure_width_coroutine(mut rx: UnboundedReceiver<(usize, usize, String, usize)>, editor: &mut Signal<EditorBuilder>) {
 
     println!("[measure widths] coroutine is running");
 
-    loop {
-        // Try to receive a message
-        match rx.try_next() {
-            Ok(Some((index_i, index_j, text))) => {
-
-                let js = format!(
-                    r#"
+    while let Some((index_i, index_j, text, font_size)) = rx.next().await {
+        let js = format!(
+            r#"
     
-                    let span = document.createElement('span');
-                    span.style.fontFamily = 'CqMono';
-                    span.style.fontSize = '16px';
-                    span.style.padding = '0px';
-                    span.style.lineHeight = '1.2';
-                    span.style.position = 'absolute';
-                    span.style.visibility = 'hidden';
-                    span.style.whiteSpace = 'pre';
-                    span.textContent = '{}';
-                    document.body.appendChild(span);
-                    let width = span.offsetWidth + 0; // Add 2px padding * 2 + 1px border * 2
-                    document.body.removeChild(span);
-                    return width;
+            let span = document.createElement('span');
+            span.style.fontFamily = 'CqMono';
+            span.style.fontSize = '{}px';
+            span.style.padding = '0px';
+            span.style.lineHeight = '1.2';
+            span.style.position = 'absolute';
+            span.style.visibility = 'hidden';
+            span.style.whiteSpace = 'pre';
+            span.textContent = {};
+            document.body.appendChild(span);
+            let width = span.offsetWidth + 0; // Add 2px padding * 2 + 1px border * 2
+            document.body.removeChild(span);
+            return width;
     
-                    "#,
-                    text
-                );
+            "#,
+            font_size,
+            // Passed as a JSON string, so quotes and backslashes in the text are safe
+            serde_json::to_string(&text).unwrap_or_default()
+        );
     
-                if let Ok(result) = document::eval(&js).await {
-                    
-                    let maybe_width = result.as_f64();
+        if let Ok(result) = document::eval(&js).await {
+            
+            let maybe_width = result.as_f64();
     
-                    // let flat_index = editor.read().raw_text.iter().take(index_i).map(|inner| inner.len()).sum::<usize>() + index_j;
+            // let flat_index = editor.read().raw_text.iter().take(index_i).map(|inner| inner.len()).sum::<usize>() + index_j;
 
-                    // let row_size = editor.read().text_width.len();
+            // let row_size = editor.read().text_width.len();
 
-                    while index_i >= editor.read().text_width.len() {
-                        // editor.write().text_width
-                        editor.write().text_width.push(vec![None]);
-                    }
+            while index_i >= editor.read().text_width.len() {
+                // editor.write().text_width
+                editor.write().text_width.push(vec![None]);
+            }
 
-                    while index_j >= editor.read().text_width[index_i].len() {
-                        // editor.write().text_width
-                        editor.write().text_width[index_i].push(None);
-                    }
+            while index_j >= editor.read().text_width[index_i].len() {
+                // editor.write().text_width
+                editor.write().text_width[index_i].push(None);
+            }
 
-                    editor.write().text_width[index_i][index_j] = maybe_width;
+            editor.write().text_width[index_i][index_j] = maybe_width;
 
-                }
-            }
-            Ok(None) => {
-                tokio::time::sleep(Duration::from_millis(10)).await;
-            }
-            Err(_) => {
-                tokio::time::sleep(Duration::from_millis(10)).await;
-            }
         }
     }
 }
 
 
-pub async fn focus_caret_position_coroutine(mut rx: UnboundedReceiver<(usize, usize)>, editor: &mut Signal<EditorBuilder>) {
-
-    loop {
-        // Try to receive a message
-        match rx.try_next() {
-            Ok(Some((index_i, index_j))) => {
-
-                let element_id = get_element_id(index_i, index_j);
-                // JavaScript to get the caret position within the element
-                let js_code = format!(
-                    r#"
-                    return window.getCaretClickPosition('{}');
-                    "#,
-                    element_id
-                );
+/// Reads the caret of a cell from the DOM as a byte offset into the cell's text.
+/// The DOM counts in UTF-16 code units, so the cell's text is read along with it.
+/// The cell is given by its element id, see `State::element_id`.
+pub async fn get_dom_caret_position(element_id: String) -> Option<usize> {
+    let js_code = format!(
+        r#"
+        return [window.getCaretClickPosition('{0}'), window.getElementText('{0}')];
+        "#,
+        element_id
+    );
 
-                match document::eval(&js_code).await {
-                    Ok(result) => {
-                        if let Some(pos) = result.as_i64() {
-                            let pos = pos as usize;
-                            println!("Caret position for index {} {}: {}", index_i, index_j, pos);
-                            editor.with_mut(|e| e.move_caret(index_i, index_j, pos));
-                        } else {
-                            eprintln!("Failed to parse caret position: {:?}", result);
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("JS eval error: {}", e);
-                    }
-                    
-                }
-            }
-            Ok(None) => {
-                // No messages available, wait for a short time before trying again
-                tokio::time::sleep(Duration::from_millis(1)).await;
-            }
+    match document::eval(&js_code).await {
+        Ok(result) => match serde_json::from_value::<(usize, String)>(result.clone()) {
+            Ok((dom_pos, text)) => Some(TextOffset::from_utf16(&text, dom_pos).byte()),
             Err(_) => {
-                tokio::time::sleep(Duration::from_millis(1)).await;
-                // Channel is closed, exit the loop
-                // println!("Channel closed");
-                // break;
+                eprintln!("Failed to parse caret position: {:?}", result);
+                None
             }
+        },
+        Err(e) => {
+            eprintln!("JS eval error: {}", e);
+            None
         }
     }
-
 }
 
+pub async fn focus_caret_position_coroutine(mut rx: UnboundedReceiver<(usize, usize)>, editor: &mut Signal<EditorBuilder>) {
 
-
-pub async fn update_editor_text_coroutine(mut rx: UnboundedReceiver<(usize, usize, usize, String)>, editor: &mut Signal<EditorBuilder>) {
-
-    loop {
-        // Try to receive a message
-        match rx.try_next() {
-            Ok(Some((index_i, index_j, cursor_pos, new_text))) => {
-                update_editor_text_coroutine_single(index_i, index_j, cursor_pos, new_text, editor).await;
-            }
-            Ok(None) => {
-                // No messages available, wait for a short time before trying again
-                tokio::time::sleep(Duration::from_micros(10)).await;
-            }
-            Err(_) => {
-                tokio::time::sleep(Duration::from_micros(10)).await;
-                // Channel is closed, exit the loop
-                // println!("Channel closed");
-                // break;
-            }
+    while let Some((index_i, index_j)) = rx.next().await {
+        let Some(element_id) = editor.read().element_id(index_i, index_j) else {
+            continue;
+        };
+        if let Some(pos) = get_dom_caret_position(element_id).await {
+            println!("Caret position for index {} {}: {}", index_i, index_j, pos);
+            editor.with_mut(|e| e.move_caret(index_i, index_j, pos));
         }
     }
 
-
 }
-
-pub async fn update_editor_text_coroutine_single(index_i: usize, index_j: usize, cursor_pos: usize, new_text: String, editor: &mut Signal<EditorBuilder>) {
-    // tokio::time::sleep(Duration::from_millis(1)).await;
-
-    let element_id = get_element_id(index_i, index_j);
-    let text_b64 = general_purpose::STANDARD.encode(new_text.clone());
-
-    let js = format!(
-        r#"
-        return window.clearElementTextWithPosition('{}', atob("{}"), {});
-        "#,
-        element_id,
-        text_b64,
-        cursor_pos
-    );
-
-    let _ = document::eval(&js).await;
-
-    editor.write().update_text(index_i, index_j, new_text.clone());
-
-    editor.write().move_caret(index_i, index_j , cursor_pos);
-
-    println!("[update_editor_text_coroutine_single] index i {} index j {} cursor {} text {}", index_i, index_j, cursor_pos, new_text.clone());
-}
\ No newline at end of file
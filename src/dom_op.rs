@@ -0,0 +1,30 @@
+// src/dom_op.rs
+use crate::selection::Position;
+
+/// One update to the editor, queued in `dom_updates` and applied in order by
+/// the `update_editor_dom` task, which keeps `raw_text` and the DOM in step.
+/// Rows and columns index `raw_text`; carets are byte offsets into a cell.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DomOp {
+    /// Sets the text of a cell, leaving the caret where it is.
+    UpdateText { row: usize, col: usize, text: String },
+    /// Sets the text of a cell and puts the caret at `caret` in it.
+    UpdateTextCursor { row: usize, col: usize, caret: usize, text: String },
+    /// Inserts `cells` as a new row below `row` and moves the caret to its start.
+    CreateRow { row: usize, cells: Vec<String> },
+    /// Removes a row; the rows below move up.
+    DeleteRow { row: usize },
+    /// Appends the cells of the row below `row` to it, with the caret at the join.
+    /// The row below is removed by a following `DeleteRow`.
+    UpdateRow { row: usize },
+    Undo,
+    Redo,
+    /// Deletes the text between two positions and types `text` in its place.
+    DeleteRange { anchor: Position, focus: Position, text: String },
+    /// Focuses a cell with the caret at `caret`.
+    FocusElement { row: usize, col: usize, caret: usize },
+    /// While `true`, ops are applied without re-running the syntax pass after each one.
+    InternalProcess(bool),
+    /// Removes a cell.
+    DeleteElement { row: usize, col: usize },
+}
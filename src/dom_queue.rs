@@ -0,0 +1,88 @@
+// src/dom_queue.rs
+use std::collections::VecDeque;
+
+use dioxus::prelude::*;
+use futures_channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures_channel::oneshot;
+
+use crate::dom_op::DomOp;
+
+/// The queue between the handlers and the task that applies `DomOp`s.
+///
+/// Ops are sent over a channel, so the applying task sleeps until there is
+/// work. The queue counts the ops that are sent but not applied yet; tasks
+/// that must see the editor after every queued op (the handlers, before they
+/// read the caret) await `idle`, which resolves once the count drops to zero.
+#[derive(Clone, Copy)]
+pub struct DomQueue {
+    sender: CopyValue<UnboundedSender<DomOp>>,
+    receiver: CopyValue<Option<UnboundedReceiver<DomOp>>>,
+    pending: CopyValue<usize>,
+    idle_waiters: CopyValue<VecDeque<oneshot::Sender<()>>>,
+}
+
+impl DomQueue {
+    /// Creates the queue. Call inside a component, e.g. `use_hook(DomQueue::new)`.
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::unbounded();
+        Self {
+            sender: CopyValue::new(sender),
+            receiver: CopyValue::new(Some(receiver)),
+            pending: CopyValue::new(0),
+            idle_waiters: CopyValue::new(VecDeque::new()),
+        }
+    }
+
+    /// Hands the receiving end to the task that applies the ops. Returns `None` after the first call.
+    pub fn take_receiver(&mut self) -> Option<UnboundedReceiver<DomOp>> {
+        self.receiver.write().take()
+    }
+
+    pub fn push(&mut self, op: DomOp) {
+        if self.sender.read().unbounded_send(op).is_ok() {
+            *self.pending.write() += 1;
+        }
+    }
+
+    pub fn extend(&mut self, ops: impl IntoIterator<Item = DomOp>) {
+        for op in ops {
+            self.push(op);
+        }
+    }
+
+    /// The number of ops sent but not applied yet.
+    pub fn len(&self) -> usize {
+        *self.pending.read()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Called by the applying task after each op. Wakes the `idle` waiters once
+    /// no ops are left.
+    pub fn done(&mut self) {
+        let pending = {
+            let mut pending = self.pending.write();
+            *pending = pending.saturating_sub(1);
+            *pending
+        };
+        if pending == 0 {
+            for waiter in self.idle_waiters.write().drain(..) {
+                let _ = waiter.send(());
+            }
+        }
+    }
+
+    /// Resolves once every queued op has been applied. Waiters are woken in
+    /// the order they started waiting, so keys pressed while the queue is busy
+    /// are applied in the order they were pressed.
+    pub async fn idle(&self) {
+        let mut idle_waiters = self.idle_waiters;
+        while !self.is_empty() {
+            let (waiter, woken) = oneshot::channel();
+            idle_waiters.write().push_back(waiter);
+            let _ = woken.await;
+        }
+    }
+}
@@ -0,0 +1,912 @@
+// src/editor_core.rs
+use std::ops::Range;
+
+use crate::buffer::{Document, RowId};
+use crate::dom_op::DomOp;
+use crate::history::{EditKind, InverseOp};
+use crate::offset::TextOffset;
+use crate::selection::{self, Position};
+use crate::state::State;
+use crate::syntax::block::LineBlock;
+use crate::syntax::text::{ListMarker, RowPatch, TextProcessor};
+
+/// A key the editor reacts to, independent of the UI toolkit.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EditKey {
+    Character(String),
+    Enter,
+    Backspace,
+    Delete,
+    ArrowLeft,
+    ArrowRight,
+    ArrowUp,
+    ArrowDown,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+}
+
+/// A key press with the modifiers the editor cares about. `ctrl` is Ctrl or
+/// Cmd: it makes arrows and Backspace/Delete work by word, and Home/End jump
+/// to the start/end of the document. `shift` extends the selection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyPress {
+    pub key: EditKey,
+    pub ctrl: bool,
+    pub shift: bool,
+}
+
+impl KeyPress {
+    pub fn new(key: EditKey) -> Self {
+        Self { key, ctrl: false, shift: false }
+    }
+
+    pub fn with_ctrl(mut self) -> Self {
+        self.ctrl = true;
+        self
+    }
+
+    pub fn with_shift(mut self) -> Self {
+        self.shift = true;
+        self
+    }
+}
+
+/// The view that mirrors `State.raw_text`. `EditorCore::apply` updates the
+/// state first, then tells the view what to redraw: the webview in the app
+/// (`js_dom::JsDom`), a plain grid of strings in the tests.
+pub trait Dom {
+    /// Sets the text of a cell. With a caret, also focuses the cell and puts the caret there.
+    fn set_text(&mut self, row: usize, col: usize, text: &str, caret: Option<usize>);
+    /// Redraws a row whose cells were split or merged.
+    fn set_row(&mut self, row: usize, cells: &[String]);
+    /// Inserts the row with the id `id` before `row`; the rows below move down one.
+    fn insert_row(&mut self, row: usize, id: RowId, cells: &[String]);
+    /// Removes a row; the rows below move up one.
+    fn remove_row(&mut self, row: usize);
+    fn delete_cell(&mut self, row: usize, col: usize);
+    fn focus(&mut self, row: usize, col: usize, caret: usize);
+}
+
+/// The editing logic, without a webview: turns key presses into `DomOp`s and
+/// applies `DomOp`s to the `State`. Every op goes through `apply`, so the
+/// state and the view stay in step whichever `Dom` is driven.
+pub struct EditorCore<'a> {
+    state: &'a mut State,
+    page_rows: usize,
+}
+
+impl<'a> EditorCore<'a> {
+    pub fn new(state: &'a mut State) -> Self {
+        Self { state, page_rows: 20 }
+    }
+
+    /// How many rows PageUp / PageDown move, i.e. the rows that fit in the view.
+    pub fn with_page_rows(mut self, page_rows: usize) -> Self {
+        self.page_rows = page_rows.max(1);
+        self
+    }
+
+    /// Handles a key press at the caret and returns the ops that carry it out,
+    /// in order. `cell` is the cell the key was pressed in, used when there is
+    /// no caret. Edits are recorded as one history step.
+    pub fn key_down(&mut self, press: &KeyPress, cell: (usize, usize)) -> Vec<DomOp> {
+        let caret = self.state.caret_pos.unwrap_or((cell.0, cell.1, 0));
+        match &press.key {
+            EditKey::Character(text) => self.insert(caret, text),
+            EditKey::Enter => self.enter(caret),
+            EditKey::Backspace => self.backspace(caret, press.ctrl),
+            EditKey::Delete => self.delete(caret, press.ctrl),
+            _ if press.shift => {
+                self.extend_selection(caret, press);
+                Vec::new()
+            }
+            _ => self.navigate(caret, press),
+        }
+    }
+
+    /// Types `text` at the caret, replacing the selection if there is one.
+    fn insert(&mut self, caret: Position, text: &str) -> Vec<DomOp> {
+        self.state.begin_history_step(EditKind::Insert);
+
+        if let Some(selection) = self.state.active_selection() {
+            return vec![DomOp::DeleteRange { anchor: selection.anchor, focus: selection.head, text: text.to_string() }];
+        }
+
+        let (index_i, index_j, caret_pos) = caret;
+        let mut cur_text = self.state.cell_text(index_i, index_j);
+        let caret_pos = TextOffset::from_byte(&cur_text, caret_pos).byte();
+        cur_text.insert_str(caret_pos, text);
+
+        vec![DomOp::UpdateTextCursor { row: index_i, col: index_j, caret: caret_pos + text.len(), text: cur_text }]
+    }
+
+    /// Splits the row at the caret: the text after it, and the cells after the
+    /// caret's cell, move to a new row below.
+    fn enter(&mut self, (index_i, index_j, caret_pos): Position) -> Vec<DomOp> {
+        let Some(current_row) = self.state.raw_text.row(index_i) else {
+            return Vec::new();
+        };
+        let Some(current_text) = current_row.get(index_j).cloned() else {
+            return Vec::new();
+        };
+        let block = self.state.line_block(index_i);
+
+        // Never split a fence line, so the info string stays on the fence
+        let caret_pos = if matches!(block, LineBlock::CodeFence(_)) { current_text.len() } else { caret_pos };
+        let caret_pos = TextOffset::from_byte(&current_text, caret_pos).byte();
+        let (before, after) = current_text.split_at(caret_pos);
+
+        self.state.begin_history_step(EditKind::Other);
+
+        // Inside a list item, the new row continues the list
+        let row_text = current_row.concat();
+        let row_caret = selection::row_offset(&current_row, index_j, caret_pos);
+        let list_marker = ListMarker::parse(&row_text)
+            .filter(|marker| row_caret >= marker.len && !block.is_code());
+
+        if let Some(marker) = &list_marker {
+            if row_text[marker.len..].trim().is_empty() {
+                // Enter on an empty item ends the list instead of adding another one
+                let mut ops: Vec<DomOp> = (1..current_row.len())
+                    .rev()
+                    .map(|col| DomOp::UpdateText { row: index_i, col, text: String::new() })
+                    .collect();
+                ops.push(DomOp::UpdateTextCursor { row: index_i, col: 0, caret: 0, text: String::new() });
+                return ops;
+            }
+        }
+        let continuation = match block {
+            // Inside a code block, keep the indentation of the current line
+            LineBlock::Code(_) => {
+                let indent = row_text.len() - row_text.trim_start_matches([' ', '\t']).len();
+                row_text[..indent.min(row_caret)].to_string()
+            }
+            _ => list_marker.map(|marker| marker.next()).unwrap_or_default(),
+        };
+
+        let mut new_row = vec![format!("{}{}", continuation, after)];
+        new_row.extend_from_slice(&current_row[index_j + 1..]);
+
+        // The caret's cell keeps the text before the caret, the cells after it move down
+        let mut ops = vec![DomOp::UpdateText { row: index_i, col: index_j, text: before.to_string() }];
+        ops.extend((index_j + 1..current_row.len()).rev().map(|col| DomOp::DeleteElement { row: index_i, col }));
+        ops.push(DomOp::CreateRow { row: index_i, cells: new_row });
+        ops.push(DomOp::FocusElement { row: index_i + 1, col: 0, caret: continuation.len() });
+        ops
+    }
+
+    /// Types `text`, which may hold several lines, at the caret. The first line
+    /// goes into the caret's cell; each further line becomes a new row, and the
+    /// rest of the caret's row moves to the end of the last one.
+    pub fn paste(&mut self, (index_i, index_j, caret_pos): Position, text: &str) -> Vec<DomOp> {
+        let Some(current_row) = self.state.raw_text.row(index_i) else {
+            return Vec::new();
+        };
+        let Some(current_text) = current_row.get(index_j) else {
+            return Vec::new();
+        };
+        let (before, after) = current_text.split_at(TextOffset::from_byte(current_text, caret_pos).byte());
+
+        let mut lines = text.split('\n');
+        let first_line = lines.next().unwrap_or_default();
+        let other_lines: Vec<&str> = lines.collect();
+        let Some((last_line, middle_lines)) = other_lines.split_last() else {
+            let new_text = format!("{}{}{}", before, first_line, after);
+            return vec![DomOp::UpdateTextCursor { row: index_i, col: index_j, caret: before.len() + first_line.len(), text: new_text }];
+        };
+
+        // The cells after the caret's cell move to the last row
+        let mut ops = vec![DomOp::UpdateText { row: index_i, col: index_j, text: format!("{}{}", before, first_line) }];
+        ops.extend((index_j + 1..current_row.len()).rev().map(|col| DomOp::DeleteElement { row: index_i, col }));
+        for (offset, line) in middle_lines.iter().enumerate() {
+            ops.push(DomOp::CreateRow { row: index_i + offset, cells: vec![line.to_string()] });
+        }
+        let mut last_row = vec![format!("{}{}", last_line, after)];
+        last_row.extend_from_slice(&current_row[index_j + 1..]);
+        let last_i = index_i + other_lines.len();
+        ops.push(DomOp::CreateRow { row: last_i - 1, cells: last_row });
+        ops.push(DomOp::FocusElement { row: last_i, col: 0, caret: last_line.len() });
+        ops
+    }
+
+    /// Deletes the grapheme before the caret, merging rows and cells at their start.
+    fn backspace(&mut self, caret: Position, by_word: bool) -> Vec<DomOp> {
+        self.state.begin_history_step(EditKind::Other);
+
+        // With a selection, Backspace only deletes the selected text
+        if let Some(selection) = self.state.active_selection() {
+            return vec![DomOp::DeleteRange { anchor: selection.anchor, focus: selection.head, text: String::new() }];
+        }
+
+        // Ctrl+Backspace deletes back to the start of the previous word
+        if by_word {
+            return self.delete_word(caret, selection::word_left);
+        }
+
+        let (index_i, index_j, caret_pos) = caret;
+        let cur_text = self.state.cell_text(index_i, index_j);
+        let e_pos = TextOffset::from_byte(&cur_text, caret_pos).byte();
+        // Backspace removes the whole grapheme before the caret, e.g. an accented letter or emoji
+        let prev_pos = TextOffset::from_byte(&cur_text, e_pos).prev(&cur_text).byte();
+
+        let fence_boundary = index_i > 0 && {
+            let is_fence = |row: usize| matches!(self.state.line_block(row), LineBlock::CodeFence(_));
+            !cur_text.is_empty() && (is_fence(index_i) || is_fence(index_i - 1))
+        };
+
+        if e_pos == 0 && index_j == 0 && fence_boundary {
+            // Merging into or out of a fence line would break the code block, so only move the caret
+            let (prev_i, prev_j, prev_len) = selection::row_end(&self.state.raw_text, index_i - 1);
+            self.state.move_caret(prev_i, prev_j, prev_len);
+            vec![DomOp::FocusElement { row: prev_i, col: prev_j, caret: prev_len }]
+        } else if e_pos == 0 && index_i > 0 && index_j == 0 {
+            // Append the row to the previous one, then remove it
+            vec![DomOp::UpdateRow { row: index_i - 1 }, DomOp::DeleteRow { row: index_i }]
+        } else if e_pos > 0 && prev_pos == 0 && index_j > 0 {
+            // Deleting the first grapheme of a cell merges the rest into the previous cell
+            let prev_j = index_j - 1;
+            let mut prev_text = self.state.cell_text(index_i, prev_j);
+            let join = prev_text.len();
+            prev_text.push_str(&cur_text[e_pos..]);
+
+            vec![
+                DomOp::UpdateText { row: index_i, col: index_j, text: String::new() },
+                DomOp::UpdateTextCursor { row: index_i, col: prev_j, caret: join, text: prev_text },
+            ]
+        } else if e_pos > 0 {
+            let mut new_text = cur_text;
+            new_text.replace_range(prev_pos..e_pos, "");
+            vec![DomOp::UpdateTextCursor { row: index_i, col: index_j, caret: prev_pos, text: new_text }]
+        } else {
+            // At the start of a cell other than the first, or of the document
+            Vec::new()
+        }
+    }
+
+    /// The forward counterpart of `backspace`.
+    fn delete(&mut self, caret: Position, by_word: bool) -> Vec<DomOp> {
+        self.state.begin_history_step(EditKind::Other);
+
+        // With a selection, Delete only deletes the selected text
+        if let Some(selection) = self.state.active_selection() {
+            return vec![DomOp::DeleteRange { anchor: selection.anchor, focus: selection.head, text: String::new() }];
+        }
+
+        // Ctrl+Delete deletes up to the end of the next word
+        if by_word {
+            return self.delete_word(caret, selection::word_right);
+        }
+
+        let (cur_i, cur_j, cur_pos) = caret;
+        let row = self.state.raw_text.row(cur_i).unwrap_or_default();
+        let Some(cur_text) = row.get(cur_j).cloned() else {
+            return Vec::new();
+        };
+        let cur_pos = TextOffset::from_byte(&cur_text, cur_pos).byte();
+
+        if cur_pos < cur_text.len() {
+            // Delete the grapheme after the caret
+            let mut new_text = cur_text.clone();
+            let next_pos = TextOffset::from_byte(&cur_text, cur_pos).next(&cur_text).byte();
+            new_text.replace_range(cur_pos..next_pos, "");
+            vec![DomOp::UpdateTextCursor { row: cur_i, col: cur_j, caret: cur_pos, text: new_text }]
+        } else if cur_j + 1 < row.len() {
+            // At the end of a cell: merge the next cell in, minus its first character
+            let mut next_text = row[cur_j + 1].clone();
+            let first_len = TextOffset::default().next(&next_text).byte();
+            next_text.replace_range(..first_len, "");
+            let merged = format!("{}{}", cur_text, next_text);
+
+            vec![
+                DomOp::UpdateText { row: cur_i, col: cur_j + 1, text: String::new() },
+                DomOp::UpdateTextCursor { row: cur_i, col: cur_j, caret: cur_pos, text: merged },
+            ]
+        } else if cur_i + 1 < self.state.raw_text.len() {
+            // At the end of the row: pull the next row up, unless that would merge a code fence
+            let is_fence = |row: usize| matches!(self.state.line_block(row), LineBlock::CodeFence(_));
+            if !self.state.raw_text.line(cur_i + 1).unwrap_or_default().is_empty() && (is_fence(cur_i) || is_fence(cur_i + 1)) {
+                return Vec::new();
+            }
+            vec![DomOp::UpdateRow { row: cur_i }, DomOp::DeleteRow { row: cur_i + 1 }]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Deletes from the caret to where `word_boundary` moves it, which may be
+    /// in another cell or row.
+    fn delete_word(&mut self, caret: Position, word_boundary: fn(&Document, Position) -> Position) -> Vec<DomOp> {
+        let target = word_boundary(&self.state.raw_text, caret);
+        if caret == target {
+            return Vec::new();
+        }
+        vec![DomOp::DeleteRange { anchor: caret, focus: target, text: String::new() }]
+    }
+
+    /// Moves the caret for an arrow, Home/End or Page key and drops the selection.
+    fn navigate(&mut self, caret: Position, press: &KeyPress) -> Vec<DomOp> {
+        let Some(row) = self.state.raw_text.row(caret.0) else {
+            return Vec::new();
+        };
+        // The row-level offset is what Home/End and paging reason about
+        let head = selection::position_at(&row, caret.0, selection::row_offset(&row, caret.1, caret.2));
+        let (index_i, index_j, char_pos) = navigation_target(&self.state.raw_text, &press.key, press.ctrl, head, self.page_rows);
+
+        self.state.history.break_coalescing();
+        self.state.clear_selection();
+        self.state.move_caret(index_i, index_j, char_pos);
+        vec![DomOp::FocusElement { row: index_i, col: index_j, caret: char_pos }]
+    }
+
+    /// Moves the head of the selection, starting one at the caret if there is none.
+    fn extend_selection(&mut self, caret: Position, press: &KeyPress) {
+        let head = self.state.selection.map(|selection| selection.head).unwrap_or(caret);
+        let new_head = navigation_target(&self.state.raw_text, &press.key, press.ctrl, head, self.page_rows);
+        self.state.history.break_coalescing();
+        self.state.extend_selection(new_head);
+    }
+
+    /// Applies one op to the state, then redraws what it changed on `dom`.
+    /// `InternalProcess` only concerns the syntax pass and is left to the caller.
+    /// The parse is brought up to date afterwards, so the view renders from it
+    /// and the next key reads it as the document now is. Returns the rows whose
+    /// parse changed, the ones the syntax pass has to check.
+    pub fn apply(&mut self, op: &DomOp, dom: &mut impl Dom) -> RowPatch {
+        self.apply_op(op, dom);
+        self.state.reparse()
+    }
+
+    fn apply_op(&mut self, op: &DomOp, dom: &mut impl Dom) {
+        match op {
+            DomOp::UpdateText { row, col, text } => {
+                self.state.update_text(*row, *col, text.clone());
+                dom.set_text(*row, *col, text, None);
+            }
+            DomOp::UpdateTextCursor { row, col, caret, text } => {
+                self.state.update_text(*row, *col, text.clone());
+                self.state.move_caret(*row, *col, *caret);
+                dom.set_text(*row, *col, text, Some(*caret));
+            }
+            DomOp::CreateRow { row, cells } => {
+                let id = self.state.insert_row(row + 1, cells.clone());
+                self.state.move_caret(row + 1, 0, 0);
+                // The split row lost the cells after the caret
+                dom.set_row(*row, &self.state.raw_text.row(*row).unwrap_or_default());
+                dom.insert_row(row + 1, id, cells);
+            }
+            DomOp::DeleteRow { row } => {
+                if self.state.remove_row(*row).is_some() {
+                    dom.remove_row(*row);
+                }
+            }
+            DomOp::UpdateRow { row } => {
+                let row = *row;
+                let (Some(mut cells), Some(next_row)) = (self.state.raw_text.row(row), self.state.raw_text.row(row + 1)) else {
+                    return;
+                };
+                let col = cells.len() - 1;
+                let caret = cells[col].len();
+
+                self.state.record_row(row);
+                let mut next_cells = next_row.into_iter();
+                if let Some(first) = next_cells.next() {
+                    cells[col].push_str(&first);
+                }
+                cells.extend(next_cells);
+                self.state.raw_text.set_row(row, &cells);
+                self.state.mark_dirty();
+                self.state.move_caret(row, col, caret);
+
+                dom.set_row(row, &cells);
+                dom.focus(row, col, caret);
+            }
+            DomOp::Undo | DomOp::Redo => {
+                let restored = if *op == DomOp::Redo { self.state.redo() } else { self.state.undo() };
+                if let Some(restored) = restored {
+                    for change in &restored.changes {
+                        match change {
+                            InverseOp::RestoreRow { row, cells } => dom.set_row(*row, cells),
+                            InverseOp::InsertRow { row, id, cells } => dom.insert_row(*row, *id, cells),
+                            InverseOp::RemoveRow { row } => dom.remove_row(*row),
+                        }
+                    }
+                    if let Some((index_i, index_j, char_pos)) = restored.caret {
+                        dom.focus(index_i, index_j, char_pos);
+                    }
+                }
+            }
+            DomOp::DeleteRange { anchor, focus, text } => {
+                // The rows after the first one in the range are removed, the first is rewritten
+                let len = self.state.raw_text.len();
+                let (index_i, index_j, char_pos) = self.state.replace_range(*anchor, *focus, text);
+                for _ in self.state.raw_text.len()..len {
+                    dom.remove_row(index_i + 1);
+                }
+                dom.set_row(index_i, &self.state.raw_text.row(index_i).unwrap_or_default());
+                dom.focus(index_i, index_j, char_pos);
+            }
+            DomOp::FocusElement { row, col, caret } => {
+                self.state.move_caret(*row, *col, *caret);
+                dom.focus(*row, *col, *caret);
+            }
+            DomOp::InternalProcess(_) => {}
+            DomOp::DeleteElement { row, col } => {
+                self.state.record_row(*row);
+                self.state.raw_text.update_row(*row, |cells| remove_cell(cells, *col));
+                self.state.mark_dirty();
+                dom.delete_cell(*row, *col);
+            }
+        }
+    }
+}
+
+/// Removes cell `col` from a row. A row keeps at least one cell, so the last
+/// one is emptied instead.
+pub fn remove_cell(cells: &mut Vec<String>, col: usize) {
+    if cells.len() > 1 && col < cells.len() {
+        cells.remove(col);
+    } else if let Some(cell) = cells.get_mut(col) {
+        cell.clear();
+    }
+}
+
+/// Where a navigation key moves the caret from `head`. Home/End work on the
+/// whole row across cells; with Ctrl they jump to the start/end of the document,
+/// and Ctrl+Left/Right move by word.
+pub fn navigation_target(raw_text: &Document, key: &EditKey, ctrl: bool, head: Position, page: usize) -> Position {
+    match key {
+        EditKey::ArrowLeft if ctrl => selection::word_left(raw_text, head),
+        EditKey::ArrowRight if ctrl => selection::word_right(raw_text, head),
+        EditKey::ArrowLeft => selection::step_left(raw_text, head),
+        EditKey::ArrowRight => selection::step_right(raw_text, head),
+        EditKey::ArrowUp => selection::step_up(raw_text, head),
+        EditKey::ArrowDown => selection::step_down(raw_text, head),
+        EditKey::Home if ctrl => selection::document_start(),
+        EditKey::End if ctrl => selection::document_end(raw_text),
+        EditKey::Home => selection::row_start(head.0),
+        EditKey::End => selection::row_end(raw_text, head.0),
+        EditKey::PageUp => selection::page_up(raw_text, head, page),
+        EditKey::PageDown => selection::page_down(raw_text, head, page),
+        _ => head,
+    }
+}
+
+/// The syntax pass that follows an edit, run after `State::reparse`. Each row
+/// in `rows`, which the app limits to the patched rows that are rendered, and
+/// the caret's row is checked, and a row that now splits into different cells is rewritten. If
+/// the caret's row was, the caret is put back at the same row-level offset.
+/// The row updates are wrapped in `InternalProcess` so they do not trigger
+/// another pass.
+pub fn syntax_ops(state: &State, rows: Range<usize>) -> Vec<DomOp> {
+    let Some((cur_index_i, cur_index_j, cur_caret_pos)) = state.caret_pos else {
+        return Vec::new();
+    };
+
+    let mut rows: Vec<usize> = rows.collect();
+    if !rows.contains(&cur_index_i) {
+        rows.push(cur_index_i);
+    }
+
+    let text_processor = TextProcessor::new();
+    let mut ops = Vec::new();
+    let mut caret_row = None;
+    for index_i in rows {
+        let (Some(row), Some(parsed)) = (state.raw_text.row(index_i), state.parsed_rows().get(index_i)) else {
+            continue;
+        };
+
+        // Empty cells are left over from merges; a row made only of them keeps one
+        let non_empty: Vec<String> = row.iter().filter(|text| !text.is_empty()).cloned().collect();
+        let input_row = if row.len() <= 1 {
+            row.clone()
+        } else if non_empty.is_empty() {
+            vec![String::new()]
+        } else {
+            non_empty
+        };
+        let syntax_row = text_processor.extract_strings(vec![parsed.clone()]).remove(0);
+        if input_row == syntax_row || syntax_row.is_empty() {
+            continue;
+        }
+
+        // Clear the cells past the new cell count
+        for col in (syntax_row.len()..row.len()).rev() {
+            ops.push(DomOp::UpdateText { row: index_i, col, text: String::new() });
+        }
+        // A single cell on the caret's row is written by the caret update below
+        if index_i != cur_index_i || syntax_row.len() > 1 {
+            for (col, text) in syntax_row.iter().enumerate() {
+                ops.push(DomOp::UpdateText { row: index_i, col, text: text.clone() });
+            }
+        }
+        if index_i == cur_index_i {
+            caret_row = Some(syntax_row);
+        }
+    }
+    if ops.is_empty() && caret_row.is_none() {
+        return ops;
+    }
+    ops.insert(0, DomOp::InternalProcess(true));
+    ops.push(DomOp::InternalProcess(false));
+
+    if let Some(syntax_row) = caret_row {
+        let row_level = selection::row_offset(&state.raw_text.row(cur_index_i).unwrap_or_default(), cur_index_j, cur_caret_pos);
+        let (index_i, index_j, char_pos) = selection::position_at(&syntax_row, cur_index_i, row_level);
+        ops.push(DomOp::UpdateTextCursor { row: index_i, col: index_j, caret: char_pos, text: syntax_row[index_j].clone() });
+        ops.push(DomOp::FocusElement { row: index_i, col: index_j, caret: char_pos });
+    }
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    /// The in-memory `Dom`: a grid of cell texts with the id of each row, plus
+    /// the focused caret, and the rows that were written to, to check edits
+    /// stay on the rows they change.
+    #[derive(Debug, Default)]
+    struct MemoryDom {
+        rows: Vec<Vec<String>>,
+        ids: Vec<RowId>,
+        focus: Option<Position>,
+        written: BTreeSet<usize>,
+    }
+
+    impl MemoryDom {
+        fn cell(&mut self, row: usize, col: usize) -> &mut String {
+            self.written.insert(row);
+            while self.rows.len() <= row {
+                self.rows.push(Vec::new());
+            }
+            while self.rows[row].len() <= col {
+                self.rows[row].push(String::new());
+            }
+            &mut self.rows[row][col]
+        }
+    }
+
+    impl Dom for MemoryDom {
+        fn set_text(&mut self, row: usize, col: usize, text: &str, caret: Option<usize>) {
+            *self.cell(row, col) = text.to_string();
+            if let Some(caret) = caret {
+                self.focus = Some((row, col, caret));
+            }
+        }
+
+        fn set_row(&mut self, row: usize, cells: &[String]) {
+            self.written.insert(row);
+            if let Some(current) = self.rows.get_mut(row) {
+                *current = cells.to_vec();
+            }
+        }
+
+        fn insert_row(&mut self, row: usize, id: RowId, cells: &[String]) {
+            self.written.insert(row);
+            self.rows.insert(row.min(self.rows.len()), cells.to_vec());
+            self.ids.insert(row.min(self.ids.len()), id);
+        }
+
+        fn remove_row(&mut self, row: usize) {
+            if row < self.rows.len() {
+                self.rows.remove(row);
+                self.ids.remove(row);
+            }
+        }
+
+        fn delete_cell(&mut self, row: usize, col: usize) {
+            if let Some(cells) = self.rows.get_mut(row) {
+                remove_cell(cells, col);
+            }
+        }
+
+        fn focus(&mut self, row: usize, col: usize, caret: usize) {
+            self.focus = Some((row, col, caret));
+        }
+    }
+
+    fn row_ids(state: &State) -> Vec<RowId> {
+        (0..state.raw_text.len()).filter_map(|index_i| state.raw_text.row_id(index_i)).collect()
+    }
+
+    /// Replays keys the way the app does: a key's ops are applied in order,
+    /// then the syntax pass runs until the caret's row is stable.
+    struct Editor {
+        state: State,
+        dom: MemoryDom,
+    }
+
+    impl Editor {
+        fn new(markdown: &str) -> Self {
+            let state = State::new(markdown.to_string(), None);
+            let dom = MemoryDom { rows: state.raw_text.to_rows(), ids: row_ids(&state), ..MemoryDom::default() };
+            Self { state, dom }
+        }
+
+        /// Puts the caret at a row-level offset, as a click would.
+        fn click(&mut self, row: usize, offset: usize) {
+            let (index_i, index_j, char_pos) = selection::position_at(&self.state.raw_text.row(row).unwrap(), row, offset);
+            self.state.move_caret(index_i, index_j, char_pos);
+        }
+
+        fn press(&mut self, press: KeyPress) {
+            let cell = self.state.caret_pos.map(|(index_i, index_j, _)| (index_i, index_j)).unwrap_or((0, 0));
+            let mut ops = EditorCore::new(&mut self.state).key_down(&press, cell);
+            for _ in 0..10 {
+                if ops.is_empty() {
+                    break;
+                }
+                // Like the app, only the rows whose parse changed are checked
+                let mut patched: Option<Range<usize>> = None;
+                for op in &ops {
+                    let patch = EditorCore::new(&mut self.state).apply(op, &mut self.dom);
+                    if !patch.is_empty() {
+                        patched = Some(patch.follow(patched));
+                    }
+                }
+                assert_eq!(self.dom.rows, self.state.raw_text.to_rows(), "DOM out of step after {:?}", ops);
+                assert_eq!(self.dom.ids, row_ids(&self.state), "row ids out of step after {:?}", ops);
+                assert_eq!(self.state.row_heights().len(), self.state.parsed_rows().len());
+                ops = syntax_ops(&self.state, patched.unwrap_or_default());
+            }
+            assert!(ops.is_empty(), "syntax pass did not settle: {:?}", ops);
+        }
+
+        fn key(&mut self, key: EditKey) {
+            self.press(KeyPress::new(key));
+        }
+
+        fn type_text(&mut self, text: &str) {
+            for ch in text.chars() {
+                self.key(EditKey::Character(ch.to_string()));
+            }
+        }
+
+        /// The caret as a row and a row-level offset.
+        fn caret(&self) -> (usize, usize) {
+            let (index_i, index_j, char_pos) = self.state.caret_pos.expect("no caret");
+            (index_i, selection::row_offset(&self.state.raw_text.row(index_i).unwrap(), index_j, char_pos))
+        }
+
+        fn markdown(&self) -> String {
+            self.state.to_markdown()
+        }
+    }
+
+    #[test]
+    fn test_enter_inside_bold_splits_the_row() {
+        let mut editor = Editor::new("Some **bold text** here");
+        editor.click(0, "Some **bold".len());
+        editor.key(EditKey::Enter);
+
+        assert_eq!(editor.markdown(), "Some **bold\n text** here");
+        assert_eq!(editor.caret(), (1, 0));
+
+        editor.type_text("X");
+        assert_eq!(editor.markdown(), "Some **bold\nX text** here");
+    }
+
+    #[test]
+    fn test_enter_continues_and_ends_lists() {
+        let mut editor = Editor::new("- first");
+        editor.key(EditKey::End);
+        editor.key(EditKey::Enter);
+        editor.type_text("second");
+        editor.key(EditKey::Enter);
+        editor.key(EditKey::Enter);
+
+        assert_eq!(editor.markdown(), "- first\n- second");
+    }
+
+    #[test]
+    fn test_backspace_merges_rows_and_cells() {
+        let mut editor = Editor::new("first\nsecond");
+        editor.click(1, 0);
+        editor.key(EditKey::Backspace);
+
+        assert_eq!(editor.markdown(), "firstsecond");
+        assert_eq!(editor.caret(), (0, 5));
+
+        // Deleting the first character of a cell joins it to the previous cell
+        let mut editor = Editor::new("plain **bold**");
+        editor.click(0, "plain *".len());
+        editor.key(EditKey::Backspace);
+        assert_eq!(editor.markdown(), "plain *bold**");
+        assert_eq!(editor.caret(), (0, "plain ".len()));
+
+        editor.type_text("_");
+        assert_eq!(editor.markdown(), "plain _*bold**");
+    }
+
+    #[test]
+    fn test_delete_pulls_up_the_next_row() {
+        let mut editor = Editor::new("one\ntwo");
+        editor.click(0, 3);
+        editor.key(EditKey::Delete);
+        editor.key(EditKey::Delete);
+
+        assert_eq!(editor.markdown(), "onewo");
+    }
+
+    #[test]
+    fn test_arrows_wrap_across_rows() {
+        let mut editor = Editor::new("ab\ncd");
+        editor.click(0, 2);
+        editor.key(EditKey::ArrowRight);
+        assert_eq!(editor.caret(), (1, 0));
+        editor.type_text("X");
+
+        editor.key(EditKey::Home);
+        editor.key(EditKey::ArrowLeft);
+        assert_eq!(editor.caret(), (0, 2));
+        editor.type_text("Y");
+
+        editor.key(EditKey::ArrowDown);
+        editor.type_text("Z");
+        assert_eq!(editor.markdown(), "abY\nXcdZ");
+    }
+
+    #[test]
+    fn test_word_deletion_and_undo() {
+        let mut editor = Editor::new("alpha beta gamma");
+        editor.key(EditKey::End);
+        editor.press(KeyPress::new(EditKey::Backspace).with_ctrl());
+        assert_eq!(editor.markdown(), "alpha beta ");
+
+        editor.press(KeyPress::new(EditKey::Home).with_ctrl());
+        editor.press(KeyPress::new(EditKey::ArrowRight).with_shift());
+        editor.type_text("A");
+        assert_eq!(editor.markdown(), "Alpha beta ");
+
+        editor.key(EditKey::Enter);
+        assert_eq!(editor.markdown(), "A\nlpha beta ");
+
+        // Undo is an op too, so it goes through the same path
+        let ops = vec![DomOp::Undo, DomOp::Undo, DomOp::Undo];
+        for op in &ops {
+            EditorCore::new(&mut editor.state).apply(op, &mut editor.dom);
+        }
+        assert_eq!(editor.dom.rows, editor.state.raw_text.to_rows());
+        assert_eq!(editor.markdown(), "alpha beta gamma");
+    }
+
+    #[test]
+    fn test_paste_splits_lines_into_rows_and_undoes_at_once() {
+        let mut editor = Editor::new("Some **bold** here");
+        editor.click(0, "Some".len());
+        let caret = editor.state.caret_pos.unwrap();
+        editor.state.begin_history_step(EditKind::Other);
+        let ops = EditorCore::new(&mut editor.state).paste(caret, "one\ntwo\nthree");
+        for op in &ops {
+            EditorCore::new(&mut editor.state).apply(op, &mut editor.dom);
+        }
+        assert_eq!(editor.dom.rows, editor.state.raw_text.to_rows());
+        assert_eq!(editor.markdown(), "Someone\ntwo\nthree **bold** here");
+        assert_eq!(editor.caret(), (2, "three".len()));
+
+        EditorCore::new(&mut editor.state).apply(&DomOp::Undo, &mut editor.dom);
+        assert_eq!(editor.dom.rows, editor.state.raw_text.to_rows());
+        assert_eq!(editor.markdown(), "Some **bold** here");
+
+        // A caret in a cell that no longer exists pastes nothing
+        assert!(EditorCore::new(&mut editor.state).paste((0, 9, 0), "x\ny").is_empty());
+    }
+
+    #[test]
+    fn test_cut_is_undone_on_its_own() {
+        let mut editor = Editor::new("plain text");
+        editor.click(0, "plain text".len());
+        editor.type_text(" more");
+        assert_eq!(editor.markdown(), "plain text more");
+
+        // Replays what the Ctrl+X handler queues
+        editor.state.begin_history_step(EditKind::Other);
+        let cut = DomOp::DeleteRange { anchor: (0, 0, 0), focus: (0, 0, "plain ".len()), text: String::new() };
+        EditorCore::new(&mut editor.state).apply(&cut, &mut editor.dom);
+        assert_eq!(editor.markdown(), "text more");
+
+        EditorCore::new(&mut editor.state).apply(&DomOp::Undo, &mut editor.dom);
+        assert_eq!(editor.dom.rows, editor.state.raw_text.to_rows());
+        assert_eq!(editor.markdown(), "plain text more");
+    }
+
+    #[test]
+    fn test_opening_a_fence_restyles_the_rows_below() {
+        let mut editor = Editor::new("x\n\nsay **bold** text");
+        assert_eq!(editor.state.raw_text.row(2).unwrap(), vec!["say ", "**bold**", " text"]);
+
+        editor.click(0, 0);
+        editor.type_text("```");
+        let cells: Vec<String> = editor.state.raw_text.row(2).unwrap().into_iter().filter(|text| !text.is_empty()).collect();
+        assert_eq!(cells, vec!["say **bold** text"]);
+
+        editor.key(EditKey::Backspace);
+        assert_eq!(editor.state.raw_text.row(2).unwrap(), vec!["say ", "**bold**", " text"]);
+        assert_eq!(editor.markdown(), "``x\n\nsay **bold** text");
+    }
+
+    #[test]
+    fn test_syntax_pass_leaves_rows_outside_the_range() {
+        let mut state = State::new("x\n\nsay **bold** text".to_string(), None);
+        state.move_caret(0, 0, 0);
+        state.update_text(0, 0, "```x".to_string());
+        state.reparse();
+
+        let touches_row = |ops: &[DomOp], row: usize| ops.iter().any(|op| matches!(op, DomOp::UpdateText { row: r, .. } if *r == row));
+        assert!(!touches_row(&syntax_ops(&state, 0..2), 2));
+        assert!(touches_row(&syntax_ops(&state, 0..3), 2));
+    }
+
+    #[test]
+    fn test_row_edits_only_redraw_the_rows_they_change() {
+        let source: Vec<String> = (0..100).map(|index| format!("line {}", index)).collect();
+        let mut editor = Editor::new(&source.join("\n"));
+        let written = |editor: &mut Editor| std::mem::take(&mut editor.dom.written);
+
+        editor.click(50, 2);
+        editor.key(EditKey::Enter);
+        assert_eq!(written(&mut editor), BTreeSet::from([50, 51]));
+
+        editor.key(EditKey::Backspace);
+        assert_eq!(written(&mut editor), BTreeSet::from([50]));
+        assert_eq!(editor.state.raw_text.line(51).as_deref(), Some("line 51"));
+
+        // Undoing the merge splits the row again
+        EditorCore::new(&mut editor.state).apply(&DomOp::Undo, &mut editor.dom);
+        assert_eq!(editor.dom.rows, editor.state.raw_text.to_rows());
+        assert_eq!(written(&mut editor), BTreeSet::from([50, 51]));
+    }
+
+    #[test]
+    fn test_structural_edits_keep_the_other_row_ids() {
+        let source: Vec<String> = (0..10).map(|index| format!("line {}", index)).collect();
+        let mut editor = Editor::new(&source.join("\n"));
+        let before = row_ids(&editor.state);
+
+        editor.click(5, 2);
+        editor.key(EditKey::Enter);
+        let split = row_ids(&editor.state);
+        assert_eq!(split[..6], before[..6]);
+        assert_eq!(split[7..], before[6..]);
+        assert!(!before.contains(&split[6]));
+
+        // Merging back removes the new row, and undo puts it back under its id
+        editor.key(EditKey::Backspace);
+        assert_eq!(row_ids(&editor.state), before);
+        EditorCore::new(&mut editor.state).apply(&DomOp::Undo, &mut editor.dom);
+        assert_eq!(row_ids(&editor.state), split);
+        assert_eq!(editor.dom.ids, split);
+        assert_eq!(editor.state.element_id(6, 0), Some(split[6].cell_element_id(0)));
+        assert_eq!(editor.state.row_index(split[9]), Some(9));
+    }
+
+    #[test]
+    fn test_untouched_documents_save_unchanged() {
+        for source in ["", "a", "a\n", "a\n\n\n", "\tx  \n", "|a|b|\n|-|-|\n", "one\r\ntwo\r\n", "mixed\r\nends\n"] {
+            assert_eq!(Editor::new(source).markdown(), source);
+        }
+    }
+
+    #[test]
+    fn test_saving_keeps_line_endings_and_aligns_edited_tables() {
+        let mut editor = Editor::new("|a|b|\r\n|-|-|\r\n\r\n|c|d|\r\n|-|-|\r\n");
+        editor.click(0, 1);
+        editor.type_text("x");
+        assert_eq!(editor.markdown(), "| xa  | b   |\r\n| --- | --- |\r\n\r\n|c|d|\r\n|-|-|\r\n");
+
+        // Undoing the edit puts the table back as it was loaded
+        EditorCore::new(&mut editor.state).apply(&DomOp::Undo, &mut editor.dom);
+        assert_eq!(editor.markdown(), "|a|b|\r\n|-|-|\r\n\r\n|c|d|\r\n|-|-|\r\n");
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_untouched_documents_save_unchanged(source in proptest::prop_oneof!["(?s).{0,200}", "[\u{1b}\u{1e}\u{1f}\r\n\t a#*|-]{0,60}"]) {
+            proptest::prop_assert_eq!(State::new(source.clone(), None).to_markdown(), source);
+        }
+    }
+}
@@ -0,0 +1,53 @@
+// src/font.rs
+use std::sync::OnceLock;
+
+use ttf_parser::Face;
+
+/// The font every cell is set in, see `.base-paragraph` in `assets/editor.css`.
+static CQ_MONO: &[u8] = include_bytes!("../assets/CqMono-mBXa.ttf");
+
+fn cq_mono() -> Option<&'static Face<'static>> {
+    static FACE: OnceLock<Option<Face<'static>>> = OnceLock::new();
+    FACE.get_or_init(|| Face::parse(CQ_MONO, 0).ok()).as_ref()
+}
+
+/// The width in pixels of `text` set in CqMono at `font_size` px, summed from
+/// the font's advance widths.
+///
+/// Returns `None` if the font has no glyph for one of the characters: the
+/// webview draws those in a fallback font, so only it can measure the text.
+pub fn text_width(text: &str, font_size: usize) -> Option<f64> {
+    let face = cq_mono()?;
+    let mut units = 0u32;
+    for c in text.chars() {
+        let glyph = face.glyph_index(c)?;
+        units += u32::from(face.glyph_hor_advance(glyph)?);
+    }
+    Some(f64::from(units) * font_size as f64 / f64::from(face.units_per_em()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_widths_add_up_per_character() {
+        let one = text_width("m", 16).unwrap();
+        assert!(one > 0.0);
+        assert_eq!(text_width("", 16), Some(0.0));
+        assert_eq!(text_width("mmm", 16), Some(one * 3.0));
+        assert_eq!(text_width("m", 32), Some(one * 2.0));
+    }
+
+    #[test]
+    fn test_monospaced_ascii() {
+        let width = text_width("i", 16).unwrap();
+        assert_eq!(text_width("W", 16), Some(width));
+        assert_eq!(text_width(" ", 16), Some(width));
+    }
+
+    #[test]
+    fn test_missing_glyphs_are_left_to_the_webview() {
+        assert_eq!(text_width("a\u{E000}", 16), None);
+    }
+}
@@ -1,51 +1,94 @@
 use dioxus::prelude::*;
+use dioxus::desktop::{DesktopContext, WindowCloseBehaviour};
 use crate::EditorBuilder;
-use std::collections::VecDeque;
 use std::f32::consts::E;
 use std::future::Future;
-use std::time::Duration;
+use std::ops::Range;
+use std::path::PathBuf;
 
 use super::coroutines;
-use crate::get_element_id;
-
-async fn get_editor_caret_position(index_i: usize, index_j: usize) -> Option<usize> {
-    let element_id = get_element_id(index_i, index_j);
-    // JavaScript to get the caret position within the element
-    let js_code = format!(
-        r#"
-        return window.getCaretClickPosition('{}');
-        "#,
-        element_id
-    );
+use crate::buffer::{Document, RowId};
+use crate::dom_op::DomOp;
+use crate::dom_queue::DomQueue;
+use crate::editor_core::{EditKey, EditorCore, KeyPress};
+use crate::history::EditKind;
+use crate::offset::TextOffset;
+use crate::selection::{self, Position};
+use crate::state::State;
+use crate::syntax::table;
+use crate::syntax::text::{self, TextProcessor};
+
+async fn get_editor_caret_position(element_id: String) -> Option<usize> {
+    coroutines::get_dom_caret_position(element_id).await
+}
 
-    match document::eval(&js_code).await {
-        Ok(result) => {
-            if let Some(pos) = result.as_i64() {
-                let pos = pos as usize;
-                Some(pos)
-                // println!("Caret position for index {} {}: {}", index_i, index_j, pos);
-                
-            } else {
-                eprintln!("Failed to parse caret position: {:?}", result);
-                return None
-            }
-        }
-        Err(e) => {
-            eprintln!("JS eval error: {}", e);
-            return None
-        }
-        
-    }
+/// Converts a keydown to the key press `EditorCore` understands, if it is one.
+pub fn key_press(event: &KeyboardEvent) -> Option<KeyPress> {
+    let key = match event.key() {
+        Key::Character(text) => EditKey::Character(text),
+        Key::Enter => EditKey::Enter,
+        Key::Backspace => EditKey::Backspace,
+        Key::Delete => EditKey::Delete,
+        Key::ArrowLeft => EditKey::ArrowLeft,
+        Key::ArrowRight => EditKey::ArrowRight,
+        Key::ArrowUp => EditKey::ArrowUp,
+        Key::ArrowDown => EditKey::ArrowDown,
+        Key::Home => EditKey::Home,
+        Key::End => EditKey::End,
+        Key::PageUp => EditKey::PageUp,
+        Key::PageDown => EditKey::PageDown,
+        _ => return None,
+    };
+    Some(KeyPress {
+        key,
+        ctrl: is_word_modifier(event),
+        shift: event.modifiers().shift(),
+    })
+}
+
+/// Waits for the queued DOM updates, then applies `press` to the editor through
+/// `EditorCore` and returns the ops that carry it out.
+async fn key_down(
+    press: &KeyPress,
+    index_i: usize,
+    index_j: usize,
+    page: usize,
+    editor: &mut Signal<EditorBuilder>,
+    dom_updates: &DomQueue,
+) -> Vec<DomOp> {
+    dom_updates.idle().await;
+    editor.with_mut(|e| EditorCore::new(e).with_page_rows(page).key_down(press, (index_i, index_j)))
+}
+
+/// Applies a keydown through `EditorCore` and queues the ops it returns.
+fn queue_key_press(
+    event: KeyboardEvent,
+    index_i: usize,
+    index_j: usize,
+    editor: &Signal<EditorBuilder>,
+    dom_updates: DomQueue,
+) -> Result<(), &'static str> {
+    let press = key_press(&event).ok_or("not an editing key")?;
+    event.stop_propagation();
+    event.prevent_default();
+
+    let mut editor = editor.clone();
+    let mut dom_updates = dom_updates.clone();
+    spawn(async move {
+        let ops = key_down(&press, index_i, index_j, 1, &mut editor, &dom_updates).await;
+        dom_updates.extend(ops);
+    });
+
+    Ok(())
 }
 
-// Handler for Enter key
+// Handler for Enter key: splits the row at the caret, see `EditorCore::key_down`
 pub fn handle_enter_key(
     event: KeyboardEvent,
     index_i: usize,
     index_j: usize,
     editor: &Signal<EditorBuilder>,
-    measure_width: Coroutine<(usize, usize, String)>,
-    dom_updates: Signal<VecDeque<(String, String, Option<String>)>>,
+    dom_updates: DomQueue,
 ) -> Result<(), &'static str>{
     event.stop_propagation();
     event.prevent_default();
@@ -53,590 +96,644 @@ pub fn handle_enter_key(
     let mut editor = editor.clone();
     let mut dom_updates = dom_updates.clone();
     spawn(async move {
-        editor.with_mut(|e| {
-            let (caret_i, caret_j, caret_pos) = e.get_caret_pos().unwrap_or((index_i, index_j, 0));
-            if caret_i == index_i && caret_j == index_j {
-                let current_row = e.raw_text[index_i].clone();
-                let current_text = current_row[index_j].clone();
-                let (before, after) = current_text.split_at(caret_pos);
-
-                // Update the current cell with the 'before' text
-                e.update_text(index_i, index_j, before.to_string());
-                
-                // Create a new row with the 'after' text and all subsequent columns
-                let mut new_row = vec![after.to_string()];
-                
-                // Add all columns after the current one to the new row
-                for col_idx in (index_j + 1)..current_row.len() {
-                    new_row.push(current_row[col_idx].clone());
-                }
-                println!("current row {:?}", current_row);
-                println!("new row {:?}", new_row);
-                // Clear the columns after the current one in the current row
-                e.raw_text[index_i].truncate(index_j + 1);
-                
-                measure_width.send((index_i, index_j, before.to_string()));
-                measure_width.send((index_i + 1, 0, after.to_string()));
-
-                let id1 = format!("{},{},{}", index_i, index_j, before.len());
-
-                // DOM updates: update current cell, then create new row with all data
-                dom_updates.write().push_back(("update_text".to_string(), id1, Some(before.to_string())));
-                
-
-
-                // Send create_row with all necessary data: index_i, index_j, new_row data
-                let create_row_data = format!("{}|{}|{}", index_i, index_j, serde_json::to_string(&new_row).unwrap_or_default());
-                dom_updates.write().push_back(("create_row".to_string(), format!("textrow-{}", index_i + 1), Some(create_row_data)));
-
-                // Update all columns in the new row
-                for (new_col_idx, col_content) in new_row.iter().enumerate().rev() {
-                    let id = format!("{},{},{}", index_i + 1, new_col_idx, 0);
-                    dom_updates.write().push_back(("update_text".to_string(), id, Some(col_content.clone())));
-                }
-                
-                let id = format!("{},{},{}", index_i + 1, 0, 0);
-                dom_updates.write().push_back(("update_text_cursor".to_string(), id, Some(new_row[0].to_string())));
-
-
-            }
-        });
+        let press = KeyPress::new(EditKey::Enter);
+        let ops = key_down(&press, index_i, index_j, 1, &mut editor, &dom_updates).await;
+        dom_updates.extend(ops);
     });
 
     Ok(())
 }
 
-pub fn handle_left_arrow<F, Fut>(
+// Handler for the arrow keys. The caret is read back from the DOM first, since
+// a click may have moved it without a keydown.
+pub fn handle_arrow_key<F, Fut>(
     event: KeyboardEvent,
     index_i: usize,
     index_j: usize,
     editor: &Signal<EditorBuilder>,
-    focus_element: impl Fn(usize, usize, usize) + 'static,
-    focus_caret_position: Coroutine<(usize, usize)>,
+    dom_updates: DomQueue,
     get_editor_caret_position: F,
 ) -> Result<(), &'static str>
 where
-    F: Fn(usize, usize) -> Fut + 'static,
+    F: Fn(String) -> Fut + 'static,
     Fut: std::future::Future<Output = Option<usize>> + 'static,
-    {
-    
+{
+    let press = key_press(&event)
+        .filter(|press| matches!(press.key, EditKey::ArrowLeft | EditKey::ArrowRight | EditKey::ArrowUp | EditKey::ArrowDown))
+        .ok_or("not an arrow key")?;
+    event.stop_propagation();
+    event.prevent_default();
+
     let mut editor = editor.clone();
+    let mut dom_updates = dom_updates.clone();
     spawn(async move {
-                
-
-        let (current_index_i, current_index_j, current_caret_pos) = editor.read().get_caret_pos().unwrap_or((index_i, index_j, 0));
-
-        let editor_pos = get_editor_caret_position(current_index_i, current_index_j).await;
-
-        if (current_index_i == index_i) && (current_index_j == index_j) {
-            
-            if let Some(e_pos) = editor_pos {
-
-
-            editor.with_mut(|e| {
-                
-                let (new_index_i, new_index_j, new_pos) = if (current_caret_pos == 0) && (index_j > 0) {
-                    // If the is a previous cell in the same row 
-                    (index_i, index_j - 1, e.raw_text[index_i][index_j - 1].len().saturating_sub(1))
-                } else if (current_caret_pos == 0) && (index_j == 0) && (index_i > 0){
-                    // If there is a previous row
-                    let prev_i = index_i - 1;
-                    let prev_j = e.raw_text[prev_i].len().saturating_sub(1);
-                    (prev_i, prev_j, e.raw_text[prev_i][prev_j].len())
-                } else if current_caret_pos > 0 {
-                    (index_i, index_j, current_caret_pos - 1)
-                }
-                else {
-                    (index_i, 0, 0)
-                };
-
-                if (new_index_j != index_j) || (new_index_i != index_i){
-                    focus_caret_position.send((new_index_i, new_index_j));
-                    focus_element(new_index_i, new_index_j, new_pos);
-                } else {
-                    focus_caret_position.send((new_index_i, new_index_j));
-                    focus_element(new_index_i, new_index_j, new_pos);
-                }
-            });
+        let element_id = editor.read().element_id(index_i, index_j);
+        if let Some(element_id) = element_id {
+            if let Some(e_pos) = get_editor_caret_position(element_id).await {
+                editor.with_mut(|e| e.move_caret(index_i, index_j, e_pos));
+            }
         }
+        let ops = key_down(&press, index_i, index_j, 1, &mut editor, &dom_updates).await;
+        dom_updates.extend(ops);
+    });
 
-        } else {
-            println!("({current_index_i} != {index_i}) && ({current_index_j} != {index_j})")
+    Ok(())
+}
+
+// Handler for Backspace (Ctrl+Backspace by word), see `EditorCore::key_down`
+pub fn handle_backspace(
+    event: KeyboardEvent,
+    index_i: usize,
+    index_j: usize,
+    editor: &Signal<EditorBuilder>,
+    dom_updates: DomQueue,
+) -> Result<(), &'static str> {
+    queue_key_press(event, index_i, index_j, editor, dom_updates)
+}
+
+// Handler for the Delete key, the forward counterpart of handle_backspace
+pub fn handle_delete(
+    event: KeyboardEvent,
+    index_i: usize,
+    index_j: usize,
+    editor: &Signal<EditorBuilder>,
+    dom_updates: DomQueue,
+) -> Result<(), &'static str> {
+    queue_key_press(event, index_i, index_j, editor, dom_updates)
+}
+
+pub fn handle_character_input(
+    event: KeyboardEvent,
+    index_i: usize,
+    index_j: usize,
+    editor: &Signal<EditorBuilder>,
+    dom_updates: DomQueue,
+) -> Result<(), &'static str> {
+    match event.key() {
+        Key::Character(_) => queue_key_press(event, index_i, index_j, editor, dom_updates),
+        _ => {
+            // Other keys are not handled yet, but must not edit the cell natively
+            event.stop_propagation();
+            event.prevent_default();
+            Ok(())
+        }
+    }
+}
+
+
+
+/// Returns `true` for keydowns that belong to an input method composition:
+/// keys pressed while composing, and the dead or `Process` key that starts one.
+/// They must reach the browser, so the handlers above never see them.
+pub fn is_composition_key(event: &KeyboardEvent, editor: &Signal<EditorBuilder>) -> bool {
+    event.is_composing() || editor.read().composition.is_some() || matches!(event.key(), Key::Dead | Key::Process)
+}
+
+// Handler for compositionstart (and compositionupdate, in case the start was
+// missed): remembers where the composed text goes. Until compositionend the
+// browser draws the preedit text itself, so nothing is written here.
+pub fn handle_composition_start(index_i: usize, index_j: usize, editor: &Signal<EditorBuilder>) {
+    let mut editor = editor.clone();
+    editor.with_mut(|e| e.begin_composition((index_i, index_j, 0)));
+}
+
+// Handler for compositionend: commits the composed text into raw_text once and
+// rewrites the cell, which also drops whatever preedit text the browser left
+// behind. A cancelled composition (empty text) just restores the cell.
+pub fn handle_composition_end(
+    event: CompositionEvent,
+    index_i: usize,
+    index_j: usize,
+    editor: &Signal<EditorBuilder>,
+    dom_updates: DomQueue,
+) -> Result<(), &'static str> {
+    let text = event.data();
+    let mut editor = editor.clone();
+    let mut dom_updates = dom_updates.clone();
+    // Some platforms only send compositionend, e.g. for a dead key sequence
+    let Some(range) = editor.with_mut(|e| {
+        e.begin_composition((index_i, index_j, 0));
+        e.end_composition()
+    }) else {
+        return Err("no composition in progress");
+    };
+
+    spawn(async move {
+        dom_updates.idle().await;
+
+        editor.with_mut(|e| e.begin_history_step(EditKind::Insert));
+
+        // Composing over a selection replaces it, like typing does
+        if !range.is_collapsed() && !text.is_empty() {
+            dom_updates.push(DomOp::DeleteRange { anchor: range.anchor, focus: range.head, text });
+            return;
+        }
+
+        let (index_i, index_j, caret_pos) = range.ordered().0;
+        let mut new_text = editor.read().cell_text(index_i, index_j);
+        let caret_pos = TextOffset::from_byte(&new_text, caret_pos).byte();
+        new_text.insert_str(caret_pos, &text);
+
+        dom_updates.push(DomOp::UpdateTextCursor { row: index_i, col: index_j, caret: caret_pos + text.len(), text: new_text });
+    });
+
+    Ok(())
+}
+
+/// Reads the DOM selection as (row, col, offset) positions of its anchor and
+/// focus. The DOM reports rows by `RowId` and offsets in UTF-16; they are
+/// converted to row indices and byte offsets. Returns `None` when the
+/// selection is collapsed or outside the editor.
+async fn get_dom_selection(editor: &Signal<EditorBuilder>) -> Option<((usize, usize, usize), (usize, usize, usize))> {
+    let result = match document::eval("return window.getSelectionRange();").await {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("JS eval error: {}", e);
+            return None;
+        }
+    };
+    let positions: Vec<(RowId, usize, usize)> = serde_json::from_value(result).ok()?;
+    let positions: Vec<Position> = positions
+        .into_iter()
+        .map(|(row_id, index_j, dom_pos)| {
+            let e = editor.read();
+            let index_i = e.row_index(row_id)?;
+            Some((index_i, index_j, e.offset_from_dom(index_i, index_j, dom_pos)))
+        })
+        .collect::<Option<_>>()?;
+    match positions[..] {
+        [anchor, focus] if anchor != focus => Some((anchor, focus)),
+        _ => None,
+    }
+}
+
+/// The editor's selection, or the DOM selection when the editor has none.
+async fn get_selection(editor: &Signal<EditorBuilder>) -> Option<(Position, Position)> {
+    let selection = editor.read().active_selection();
+    match selection {
+        Some(selection) => Some((selection.anchor, selection.head)),
+        None => get_dom_selection(editor).await,
+    }
+}
+
+// Handler for Ctrl+C / Ctrl+X: copies the selected cells as Markdown source
+pub fn handle_copy(
+    event: KeyboardEvent,
+    cut: bool,
+    editor: &Signal<EditorBuilder>,
+    dom_updates: DomQueue,
+) -> Result<(), &'static str> {
+    event.stop_propagation();
+    event.prevent_default();
+
+    let mut editor = editor.clone();
+    let mut dom_updates = dom_updates.clone();
+    spawn(async move {
+        // Copy what is on screen, after every edit already queued; a cut is its own history step
+        dom_updates.idle().await;
+        if cut {
+            editor.with_mut(|e| e.begin_history_step(EditKind::Other));
+        }
+
+        let Some((anchor, focus)) = get_selection(&editor).await else {
+            return;
+        };
+
+        let text = {
+            let text_processor = TextProcessor::new();
+            text_processor.markdown_to_string(editor.read().text_in_range(anchor, focus))
+        };
+        if let Err(err) = arboard::Clipboard::new().and_then(|mut clipboard| clipboard.set_text(text)) {
+            eprintln!("Failed to write clipboard: {}", err);
+            return;
+        }
+
+        if cut {
+            dom_updates.push(DomOp::DeleteRange { anchor, focus, text: String::new() });
         }
     });
 
     Ok(())
 }
 
-pub fn handle_right_arrow<F, Fut>(
+// Handler for Ctrl+V: replaces the selection with the clipboard text, which
+// `EditorCore::paste` turns into ops like any other edit.
+pub fn handle_paste(
     event: KeyboardEvent,
     index_i: usize,
     index_j: usize,
     editor: &Signal<EditorBuilder>,
-    focus_element: impl Fn(usize, usize, usize) + 'static,
-    focus_caret_position: Coroutine<(usize, usize)>,
-    get_editor_caret_position: F,
-) -> Result<(), &'static str>
-where
-    F: Fn(usize, usize) -> Fut + 'static,
-    Fut: std::future::Future<Output = Option<usize>> + 'static,
-{
+    dom_updates: DomQueue,
+) -> Result<(), &'static str> {
     event.stop_propagation();
     event.prevent_default();
 
     let mut editor = editor.clone();
+    let mut dom_updates = dom_updates.clone();
     spawn(async move {
-        let (current_index_i, current_index_j, current_caret_pos) = editor.read().get_caret_pos().unwrap_or((index_i, index_j, 0));
-
-        let editor_pos = get_editor_caret_position(current_index_i, current_index_j).await;
-        // let editor_pos = Some(current_caret_pos);
-
-        if (current_index_i == index_i) && (current_index_j == index_j) {
-            
-            if let Some(e_pos) = editor_pos {
-
-                // editor.with_mut(|e| e.move_caret(index_i, index_j, e_pos));
-
-                let current_text_len = editor.read().get_raw_text_current().len();
-                let current_col_size = editor.read().raw_text[index_i].len();
-                let current_row_size = editor.read().raw_text.len();
-                println!("[handle keydown] {} {} {}", e_pos, current_caret_pos, current_text_len);
-                
-                editor.with_mut(|e| {
-                    
-                    let (new_index_i, new_index_j, new_pos) = if (current_caret_pos >= current_text_len) && (index_j < current_col_size - 1) {
-                        // Move to next column in same row
-                        (index_i, index_j + 1, 0)
-                    } else if (current_caret_pos >= current_text_len) && (index_j == current_col_size - 1) && (index_i < current_row_size - 1) {
-                        // Move to first column of next row
-                        (index_i + 1, 0, 0)
-                    } else if current_caret_pos < current_text_len {
-                        // Move cursor forward within same cell
-                        (index_i, index_j, current_caret_pos + 1)
-                    } else {
-                        // Stay at the end (or handle edge case)
-                        (index_i, index_j, current_text_len)
-                    };
-
-                    println!("new index i {index_i}, new pos {:?}", new_pos);
-                    if (new_index_j != index_j) || (new_index_i != index_i){
-                        focus_caret_position.send((new_index_i, new_index_j));
-                        focus_element(new_index_i, new_index_j, new_pos);
-                    } else {
-                        focus_caret_position.send((new_index_i, new_index_j));
-                        focus_element(new_index_i, new_index_j, new_pos);
-                    }
-
-                });
+        let text = match arboard::Clipboard::new().and_then(|mut clipboard| clipboard.get_text()) {
+            Ok(text) => text.replace("\r\n", "\n").replace('\r', "\n"),
+            Err(err) => {
+                eprintln!("Failed to read clipboard: {}", err);
+                return;
             }
-        } else {
+        };
 
-            // editor.with_mut(|e| e.move_caret(index_i, index_j, current_caret_pos));
+        // The paste is its own history step, after every edit already queued
+        dom_updates.idle().await;
+        editor.with_mut(|e| e.begin_history_step(EditKind::Other));
 
-            println!("({current_index_i} != {index_i}) && ({current_index_j} != {index_j})")
+        // Pasting over a selection replaces it
+        if let Some((anchor, focus)) = get_selection(&editor).await {
+            dom_updates.push(DomOp::DeleteRange { anchor, focus, text: String::new() });
+            dom_updates.idle().await;
         }
+
+        let ops = editor.with_mut(|e| {
+            let caret = e.get_caret_pos().unwrap_or((index_i, index_j, 0));
+            EditorCore::new(e).paste(caret, &text)
+        });
+        dom_updates.extend(ops);
     });
 
     Ok(())
 }
 
-pub fn handle_up_arrow<F, Fut>(
+/// Draws the editor's selection as the DOM selection.
+async fn show_selection(editor: &Signal<EditorBuilder>, anchor: Position, head: Position) {
+    let (anchor_id, anchor_pos, head_id, head_pos) = {
+        let e = editor.read();
+        (
+            e.element_id(anchor.0, anchor.1),
+            e.dom_offset(anchor.0, anchor.1, anchor.2),
+            e.element_id(head.0, head.1),
+            e.dom_offset(head.0, head.1, head.2),
+        )
+    };
+    let (Some(anchor_id), Some(head_id)) = (anchor_id, head_id) else {
+        return;
+    };
+    let js = format!(
+        r#"return window.selectCellRange('{}', {}, '{}', {});"#,
+        anchor_id,
+        anchor_pos,
+        head_id,
+        head_pos
+    );
+    let _ = document::eval(&js).await;
+}
+
+fn is_navigation_key(key: &Key) -> bool {
+    matches!(
+        key,
+        Key::ArrowLeft | Key::ArrowRight | Key::ArrowUp | Key::ArrowDown | Key::Home | Key::End | Key::PageUp | Key::PageDown
+    )
+}
+
+/// How many rows PageUp / PageDown move: the rows that fit in the window.
+async fn page_rows(key: &Key) -> usize {
+    if !matches!(key, Key::PageUp | Key::PageDown) {
+        return 1;
+    }
+    match document::eval("return window.visibleRowCount();").await {
+        Ok(result) => result.as_u64().map(|rows| rows.max(1) as usize).unwrap_or(20),
+        Err(e) => {
+            eprintln!("JS eval error: {}", e);
+            20
+        }
+    }
+}
+
+// Handler for Home / End / PageUp / PageDown, Ctrl+Home / Ctrl+End and Ctrl+Left / Ctrl+Right
+pub fn handle_navigation(
     event: KeyboardEvent,
     index_i: usize,
     index_j: usize,
     editor: &Signal<EditorBuilder>,
-    focus_element: impl Fn(usize, usize, usize) + 'static,
-    focus_caret_position: Coroutine<(usize, usize)>,
-    get_editor_caret_position: F,
-) -> Result<(), &'static str>
-where
-    F: Fn(usize, usize) -> Fut + 'static,
-    Fut: std::future::Future<Output = Option<usize>> + 'static,
-{
+    dom_updates: DomQueue,
+) -> Result<(), &'static str> {
+    let key = event.key();
+    let ctrl = is_word_modifier(&event);
+    let word_move = ctrl && matches!(key, Key::ArrowLeft | Key::ArrowRight);
+    if !word_move && !matches!(key, Key::Home | Key::End | Key::PageUp | Key::PageDown) {
+        return Err("not a navigation key");
+    }
+    let press = key_press(&event).ok_or("not a navigation key")?;
     event.stop_propagation();
     event.prevent_default();
-    
+
     let mut editor = editor.clone();
+    let mut dom_updates = dom_updates.clone();
     spawn(async move {
-        let (current_index_i, current_index_j, current_caret_pos) = editor.read().get_caret_pos().unwrap_or((index_i, index_j, 0));
-
-        let editor_pos = get_editor_caret_position(current_index_i, current_index_j).await;
-
-        if (current_index_i == index_i) && (current_index_j == index_j) {
-            
-            if let Some(e_pos) = editor_pos {
-
-                editor.with_mut(|e| {
-                    
-                    let (new_index_i, new_index_j, new_pos) = if index_i > 0 {
-                        // Move to the previous row, same column position
-                        let prev_i = index_i - 1;
-                        let prev_j = if index_j < e.raw_text[prev_i].len() {
-                            index_j
-                        } else {
-                            e.raw_text[prev_i].len().saturating_sub(1)
-                        };
-                        
-                        // Try to maintain similar character position within the cell
-                        let target_text_len = e.raw_text[prev_i][prev_j].len();
-                        let new_char_pos = if e_pos < target_text_len {
-                            e_pos
-                        } else {
-                            target_text_len
-                        };
-                        
-                        (prev_i, prev_j, new_char_pos)
-                    } else {
-                        // Already at the top row, stay at current position
-                        (index_i, index_j, e_pos)
-                    };
-
-                    if (new_index_j != index_j) || (new_index_i != index_i) {
-                        focus_element(new_index_i, new_index_j, new_pos);
-                    } else {
-                        focus_caret_position.send((new_index_i, new_index_j));
-                    }
-                });
-            }
-        }
+        let page = page_rows(&key).await;
+        let ops = key_down(&press, index_i, index_j, page, &mut editor, &dom_updates).await;
+        dom_updates.extend(ops);
     });
 
     Ok(())
 }
 
-pub fn handle_down_arrow<F, Fut>(
+// Handler for Shift+Arrow / Shift+Home / Shift+End: moves the head of the
+// selection, starting one at the caret if there is none
+pub fn handle_extend_selection(
     event: KeyboardEvent,
     index_i: usize,
     index_j: usize,
     editor: &Signal<EditorBuilder>,
-    focus_element: impl Fn(usize, usize, usize) + 'static,
-    focus_caret_position: Coroutine<(usize, usize)>,
-    get_editor_caret_position: F,
-) -> Result<(), &'static str>
-where
-    F: Fn(usize, usize) -> Fut + 'static,
-    Fut: std::future::Future<Output = Option<usize>> + 'static,
-{
+    dom_updates: DomQueue,
+) -> Result<(), &'static str> {
+    let key = event.key();
+    if !is_navigation_key(&key) {
+        return Err("not a selection key");
+    }
+    let press = key_press(&event).ok_or("not a selection key")?;
     event.stop_propagation();
     event.prevent_default();
-    
+
     let mut editor = editor.clone();
     spawn(async move {
-        let (current_index_i, current_index_j, current_caret_pos) = editor.read().get_caret_pos().unwrap_or((index_i, index_j, 0));
-
-        let editor_pos = get_editor_caret_position(current_index_i, current_index_j).await;
-
-        if (current_index_i == index_i) && (current_index_j == index_j) {
-            
-            if let Some(e_pos) = editor_pos {
-
-                editor.with_mut(|e| {
-                    
-                    let (new_index_i, new_index_j, new_pos) = if index_i < e.raw_text.len().saturating_sub(1) {
-                        // Move to the next row, same column position
-                        let next_i = index_i + 1;
-                        let next_j = if index_j < e.raw_text[next_i].len() {
-                            index_j
-                        } else {
-                            e.raw_text[next_i].len().saturating_sub(1)
-                        };
-                        
-                        // Try to maintain similar character position within the cell
-                        let target_text_len = e.raw_text[next_i][next_j].len();
-                        let new_char_pos = if e_pos < target_text_len {
-                            e_pos
-                        } else {
-                            target_text_len
-                        };
-                        
-                        (next_i, next_j, new_char_pos)
-                    } else {
-                        // Already at the bottom row, stay at current position
-                        (index_i, index_j, e_pos)
-                    };
-
-                    if (new_index_j != index_j) || (new_index_i != index_i) {
-                        focus_element(new_index_i, new_index_j, new_pos);
-                    } else {
-                        focus_caret_position.send((new_index_i, new_index_j));
-                    }
-                });
-            }
-        }
+        let page = page_rows(&key).await;
+        // The caret only moves through the rows once the queued edits are in
+        dom_updates.idle().await;
+        let (anchor, head) = editor.with_mut(|e| {
+            EditorCore::new(e).with_page_rows(page).key_down(&press, (index_i, index_j));
+            let caret = e.get_caret_pos().unwrap_or((index_i, index_j, 0));
+            let selection = e.selection.unwrap_or(selection::Selection::new(caret, caret));
+            (selection.anchor, selection.head)
+        });
+        show_selection(&editor, anchor, head).await;
     });
 
     Ok(())
 }
 
-pub fn handle_backspace<F1, Fut1, F2, Fut2>(
+// Handler for mouseup: keeps the selection made by dragging across cells
+pub fn handle_mouse_selection(editor: &Signal<EditorBuilder>) {
+    let mut editor = editor.clone();
+    spawn(async move {
+        match get_dom_selection(&editor).await {
+            Some((anchor, focus)) => editor.with_mut(|e| e.set_selection(anchor, focus)),
+            None => editor.with_mut(|e| e.clear_selection()),
+        }
+    });
+}
+
+// Handler for Tab / Shift+Tab: moves between table cells, otherwise indents or
+// outdents the caret row (or every selected row) by one level
+pub fn handle_tab(
     event: KeyboardEvent,
     index_i: usize,
     index_j: usize,
     editor: &Signal<EditorBuilder>,
-    dom_updates: Signal<VecDeque<(String, String, Option<String>)>>,
-    update_editor_dom: Coroutine<(String, String, Option<String>)>,
+    dom_updates: DomQueue,
     focus_element: impl Fn(usize, usize, usize) + 'static,
-    delete_row: impl Fn(usize) + 'static,
-    get_editor_caret_position: F1,
-    get_editor_text: F2,
-) -> Result<(), &'static str>
-where
-    F1: Fn(usize, usize) -> Fut1 + 'static,
-    Fut1: std::future::Future<Output = Option<usize>> + 'static,
-    F2: Fn(usize, usize) -> Fut2 + 'static,
-    Fut2: std::future::Future<Output = Option<String>> + 'static,
-{
+) -> Result<(), &'static str> {
     event.stop_propagation();
     event.prevent_default();
-    
+
+    let outdent = event.modifiers().shift();
     let mut editor = editor.clone();
     let mut dom_updates = dom_updates.clone();
-
-    println!("editor {:?}", editor.read().raw_text);
-    
     spawn(async move {
+        // The caret, selection and table rows are read once every queued edit is applied
+        dom_updates.idle().await;
+        let caret = editor.read().get_caret_pos().unwrap_or((index_i, index_j, 0));
+        let selection = editor.read().active_selection();
+        if selection.is_none() {
+            let table = editor.read().table_at(caret.0);
+            if let Some(rows) = table {
+                table_tab(rows, caret, outdent, &mut editor, focus_element);
+                return;
+            }
+        }
+
+        let rows = match selection {
+            Some(selection) => {
+                let (start, end) = selection.ordered();
+                start.0..=end.0
+            }
+            None => caret.0..=caret.0,
+        };
 
-        while (*dom_updates.read()).len() > 0 {
-            println!("waiting for updates {}", (*dom_updates.read()).len());
-            tokio::time::sleep(Duration::from_millis(1)).await;
+        editor.with_mut(|e| e.begin_history_step(EditKind::Other));
+
+        // Leading spaces live in the first cell of a row, so only that cell changes
+        let mut shifts: Vec<(usize, isize)> = Vec::new();
+        for row in rows {
+            let (Some(first_cell), Some(row_text)) = (editor.read().raw_text.cell(row, 0), editor.read().raw_text.line(row)) else {
+                continue;
+            };
+            let (new_text, shift) = if outdent {
+                let (_, removed) = text::outdent_line(&row_text);
+                let removed = removed.min(first_cell.len());
+                (first_cell[removed..].to_string(), -(removed as isize))
+            } else {
+                let width = text::indent_width(&row_text);
+                (format!("{}{}", " ".repeat(width), first_cell), width as isize)
+            };
+            if shift == 0 {
+                continue;
+            }
+            shifts.push((row, shift));
+            dom_updates.push(DomOp::UpdateText { row, col: 0, text: new_text });
         }
 
-        let (current_index_i, current_index_j, current_caret_pos) = editor.read().get_caret_pos().unwrap_or((index_i, index_j, 0));
-        
-        // Get current text from editor.raw_text with bounds checking
-        let current_text = if current_index_i < editor.read().raw_text.len() && current_index_j < editor.read().raw_text[current_index_i].len() {
-            editor.read().raw_text[current_index_i][current_index_j].clone()
-        } else {
-            String::new()
+        let shifted = |(row, col, pos): Position| -> Position {
+            match shifts.iter().find(|(shifted_row, _)| *shifted_row == row) {
+                Some((_, shift)) if col == 0 => (row, col, pos.saturating_add_signed(*shift)),
+                _ => (row, col, pos),
+            }
         };
 
-        let e_pos = current_caret_pos;
-        let e_text = current_text;
-        
-        // let mut editor = editor.clone();
-        // Handle different backspace scenarios
-        if e_pos == 0 && index_i > 0 && index_j == 0 {
-            // Move row up: merge current row with previous row
-            editor.with_mut(|e| {
-                let prev_i = index_i - 1;
-                let current_row = e.raw_text[index_i].clone();
-                println!("[pos=0 and i>0 and j==0 and key event]");
-                        // When at the first column, merge entire row and clear the text that was moved
-                        // Determine where to place the cursor: last column if previous row has multiple columns
-                        let cursor_col_idx = if e.raw_text[prev_i].len() > 1 {
-                            // Move to last column if previous row has multiple columns
-                            e.raw_text[prev_i].len() - 1
-                        } else {
-                            // Stay at first column if previous row has only one column
-                            0
-                        };
-                        
-                        let prev_content_len = if cursor_col_idx < e.raw_text[prev_i].len() {
-                            e.raw_text[prev_i][cursor_col_idx].len()
-                        } else {
-                            0
-                        };
-                        
-                        
-                        // M erge all columns from current row into previous row
-                        // for (col_idx, col_content) in current_row.iter().enumerate() {
-                        //     e.raw_text[prev_i].push(col_content.to_string())
-
-                        // }
-                        
-                        let mut prev_len = 0usize;
-                        let mut cur_text = String::new();
-                        for (col_idx, col_content) in e.raw_text[prev_i].iter().enumerate() {
-                            cur_text.push_str(col_content);
-
-                            prev_len += col_content.len();
-
-                        }
-                        
-
-                        let mut new_row = e.raw_text[prev_i].clone();
-                
-                        // Add all columns after the current one to the new row
-                        for col_idx in 0..current_row.len() {
-                            new_row.push(current_row[col_idx].clone());
-                        }
-                        println!("current row {:?}", current_row);
-                        println!("new row {:?}", new_row);
-                        
-                        // Send create_row with all necessary data: index_i, index_j, new_row data
-                        let create_row_data = format!("{}|{}", prev_i, serde_json::to_string(&new_row).unwrap_or_default());
-                        dom_updates.write().push_back(("update_row".to_string(), format!("textrow-{}", prev_i), Some(create_row_data)));
-
-
-                        // // M erge all columns from current row into previous row
-                        // for (col_idx, col_content) in current_row.iter().enumerate() {
-                        //     cur_text.push_str(col_content);
-
-                        // }
-                        
-                        // e.raw_text[prev_i] = vec![cur_text.to_string()];
-                        // let old_col_count = e.raw_text[prev_i].len();
-                        // if old_col_count > 1 {
-                        //     for col_idx in 1..old_col_count {
-                        //         // delete_element(cur_index_i, col_idx);
-
-                        //         let element_id = get_element_id(prev_i, col_idx);
-                        //         println!("[delete element] {prev_i} {col_idx}");
-
-                        //         let delete_payload = format!("{},{}", prev_i, col_idx);
-                        //     //         dom_updates.write().push_back(("delete_element".to_string(), element_id, Some(delete_payload)));
-
-                        //         // update_editor_dom.send(("delete_element".to_string(), element_id, Some(delete_payload)));
-                        //         dom_updates.write().push_back(("update_text".to_string(), element_id, Some("".to_string())));
-                        //         // dom_updates.write().push_back(("delete_element".to_string(), element_id, Some(delete_payload)));
-                        //         // editor_updates.write().push_back((cur_index_i, col_idx, 0, String::new()));
-                        //     }
-
-                        //     // focus_element(cur_index_i, new_col_count - 1, editor.read().raw_text[cur_index_i][new_col_count - 1].len() - 1);
-                        // }
-
-                        // e.raw_text[prev_i] = vec!["".to_string()];
-                        // let id = format!("{},{},{}", prev_i, 0, prev_len);
-                        // dom_updates.write().push_back(("update_text_cursor".to_string(), id, Some(cur_text.to_string().clone())));
-
-                        // 
-
-                        // let cur_text = e.raw_text[prev_i][cursor_col_idx].to_string();
-
-
-                        // Focus on the cursor column at the merge point
-                        
-                        // Send delete_row with just the index_i - the DOM update will handle the rest
-                        dom_updates.write().push_back(("delete_row".to_string(), format!("textrow-{}", index_i), Some(index_i.to_string())));
-
-                        
-                        // focus_element(prev_i, cursor_col_idx, prev_content_len);
-
-                        // let id = format!("{},{},{}", prev_i, cursor_col_idx, prev_content_len);
-                        // dom_updates.write().push_back(("update_text_cursor".to_string(), id, Some("".to_string())));
-
-
-                        
-            });
-        } else if e_pos == 0 && index_i > 0 && index_j > 0 {
-            // When not at the first column, just clear the current cell and move cursor
-            println!("[pos=0 and i>0 and j>0 key event]");
-            // let id = format!("{},{},{}", index_i, index_j, 0);
-            // dom_updates.write().push_back(("update_text_cursor".to_string(), id, Some("".to_string())));
-            // Move to the previous column
-            // let prev_j = index_j - 1;
-            // let prev_col_len = editor.read().raw_text[index_i][prev_j].len();
-            // focus_element(index_i, prev_j, prev_col_len);
-
-        } else if e_pos == 1 && index_j > 0 {
-            // Move to previous column and delete a character
-            println!("[else pos=1 and j>0 key event]");
-            editor.with_mut(|e| {
-                let prev_j = index_j - 1;
-                
-
-
-                let mut prev_text = e.raw_text[index_i][prev_j].clone();
-                let mut cur_text = e_text.clone();
-                cur_text.remove(e_pos - 1);
-
-                prev_text.push_str(&cur_text);
-
-                // Get the new cursor position (end of previous column)
-                let new_pos = prev_text.len();
-
-                let id = format!("{},{},{}", index_i, index_j, 0);
-                dom_updates.write().push_back(("update_text".to_string(), id, Some("".to_string())));
+        dom_updates.idle().await;
 
-                // Focus on the previous column at the end
-                let id = format!("{},{},{}", index_i, prev_j, new_pos);
-                dom_updates.write().push_back(("update_text_cursor".to_string(), id, Some(prev_text.to_string())));
-
-                
-            });
-        } else if e_pos == 0 {
-            println!("[else pos=0 key event]");
-            // Normal text sync for regular backspace
-        } else {
-            println!("default");
-            // Normal backspace: delete character at cursor position
-            if e_pos > 0 && e_pos <= e_text.len() {
-                let mut cur_text = e_text.clone();
-                cur_text.remove(e_pos - 1);
-                let new_caret_pos = e_pos - 1;
-                println!("new_caret_pos {:?}", new_caret_pos);
-                // Update editor state
-
-
-                let id = format!("{},{},{}", current_index_i, current_index_j, new_caret_pos);
-                // dom_updates.write().push_back(("update_text".to_string(), id.clone(), Some(cur_text.clone())));
-                dom_updates.write().push_back(("update_text_cursor".to_string(), id, Some(cur_text)));
+        match selection {
+            Some(selection) => {
+                let (anchor, head) = (shifted(selection.anchor), shifted(selection.head));
+                editor.with_mut(|e| e.set_selection(anchor, head));
+                show_selection(&editor, anchor, head).await;
+            }
+            None => {
+                let (new_index_i, new_index_j, new_pos) = shifted(caret);
+                editor.with_mut(|e| e.move_caret(new_index_i, new_index_j, new_pos));
+                focus_element(new_index_i, new_index_j, new_pos);
             }
         }
     });
+
     Ok(())
 }
 
-pub fn handle_character_input<F1, Fut1, F2, Fut2>(
-    event: KeyboardEvent,
-    index_i: usize,
-    index_j: usize,
-    editor: &Signal<EditorBuilder>,
-    dom_updates: Signal<VecDeque<(String, String, Option<String>)>>,
-    update_editor_dom: Coroutine<(String, String, Option<String>)>,
-    get_editor_caret_position: F1,
-    get_editor_text: F2,
-) -> Result<(), &'static str>
-where
-    F1: Fn(usize, usize) -> Fut1 + 'static,
-    Fut1: std::future::Future<Output = Option<usize>> + 'static,
-    F2: Fn(usize, usize) -> Fut2 + 'static,
-    Fut2: std::future::Future<Output = Option<String>> + 'static,
-{
+/// Tab / Shift+Tab inside the table on `rows` moves the caret to the next /
+/// previous cell, skipping the delimiter row.
+fn table_tab(
+    rows: Range<usize>,
+    (index_i, index_j, _): Position,
+    backwards: bool,
+    editor: &mut Signal<EditorBuilder>,
+    focus_element: impl Fn(usize, usize, usize),
+) {
+    let target = {
+        let e = editor.read();
+        let columns: Vec<usize> = rows.clone().map(|row| e.raw_text.row_len(row)).collect();
+        table::next_cell(&columns, index_i - rows.start, index_j, backwards).map(|(row, col)| {
+            let index_i = rows.start + row;
+            (index_i, col, table::content_start(&e.cell_text(index_i, col)))
+        })
+    };
+
+    if let Some((new_index_i, new_index_j, new_pos)) = target {
+        editor.with_mut(|e| {
+            e.history.break_coalescing();
+            e.move_caret(new_index_i, new_index_j, new_pos);
+        });
+        focus_element(new_index_i, new_index_j, new_pos);
+    }
+}
+
+/// Returns `true` when Ctrl (or Cmd on macOS) is held, which makes arrows and
+/// Backspace/Delete work word by word.
+pub fn is_word_modifier(event: &KeyboardEvent) -> bool {
+    event.modifiers().ctrl() || event.modifiers().meta()
+}
+
+/// Returns `true` if `event` is Ctrl (or Cmd on macOS) plus the given character key.
+pub fn is_shortcut(event: &KeyboardEvent, key: &str) -> bool {
+    let modifiers = event.modifiers();
+    if !(modifiers.ctrl() || modifiers.meta()) {
+        return false;
+    }
+    match event.key() {
+        Key::Character(ch) => ch.eq_ignore_ascii_case(key),
+        _ => false,
+    }
+}
+
+/// Ends the current run of typed characters so the next insert is undone separately.
+pub fn break_history_coalescing(editor: &Signal<EditorBuilder>) {
     let mut editor = editor.clone();
-    let mut dom_updates = dom_updates.clone();
-    
+    editor.with_mut(|e| e.history.break_coalescing());
+}
+
+// Handler for Ctrl+Z (undo) and Ctrl+Shift+Z / Ctrl+Y (redo)
+pub fn handle_undo(
+    event: KeyboardEvent,
+    redo: bool,
+    dom_updates: DomQueue,
+) -> Result<(), &'static str> {
     event.stop_propagation();
     event.prevent_default();
 
-    match event.key() {
-        Key::Character(_) => {
-            spawn(async move {
-                let (current_index_i, current_index_j, current_caret_pos) = editor.read().get_caret_pos().unwrap_or((index_i, index_j, 0));
-                
-                // Get current text from editor.raw_text with bounds checking
-                let current_text = if current_index_i < editor.read().raw_text.len() && current_index_j < editor.read().raw_text[current_index_i].len() {
-                    editor.read().raw_text[current_index_i][current_index_j].clone()
-                } else {
-                    String::new()
-                };
-
-                // Insert the character at the caret position
-                if let Key::Character(ch) = &event.key() {
-                    let char_str = ch.to_string();
-                    let mut cur_text = current_text;
-                    let caret_pos = current_caret_pos.min(cur_text.len());
-                    cur_text.insert_str(caret_pos, &char_str);
-                    let new_caret_pos = caret_pos + char_str.len();
-                    
-                    // Update editor state
-                    // editor.with_mut(|e| {
-                    //     if current_index_i < e.raw_text.len() && current_index_j < e.raw_text[current_index_i].len() {
-                    //         e.raw_text[current_index_i][current_index_j] = cur_text.clone();
-                    //         e.move_caret(current_index_i, current_index_j, new_caret_pos);
-                    //     }
-                    // });
-
-                    let id = format!("{},{},{}", current_index_i, current_index_j, new_caret_pos);
-                    dom_updates.write().push_back(("update_text_cursor".to_string(), id, Some(cur_text)));
-                }
-            });
+    let mut dom_updates = dom_updates.clone();
+    dom_updates.push(if redo { DomOp::Redo } else { DomOp::Undo });
+
+    Ok(())
+}
+
+/// Asks before throwing away unsaved edits. Returns `true` if there is nothing
+/// to lose or the user agreed to discard the changes.
+async fn confirm_discard_changes(editor: &Signal<EditorBuilder>) -> bool {
+    if !editor.read().is_dirty() {
+        return true;
+    }
+
+    let result = rfd::AsyncMessageDialog::new()
+        .set_level(rfd::MessageLevel::Warning)
+        .set_title("Unsaved changes")
+        .set_description("The current document has unsaved changes. Discard them?")
+        .set_buttons(rfd::MessageButtons::YesNo)
+        .show()
+        .await;
+
+    result == rfd::MessageDialogResult::Yes
+}
+
+// Handler for Ctrl+O and the toolbar "Open" button
+pub fn handle_open_file(
+    path: Option<PathBuf>,
+    editor: &Signal<EditorBuilder>,
+    visual_editor: Signal<Document>,
+    dom_updates: DomQueue,
+) -> Result<(), &'static str> {
+    let mut editor = editor.clone();
+    let mut visual_editor = visual_editor.clone();
+    let dom_updates = dom_updates.clone();
+
+    spawn(async move {
+        if !confirm_discard_changes(&editor).await {
+            return;
         }
-        _ => {
-            // Handle other keys if needed
+
+        let path = match path {
+            Some(path) => path,
+            None => match rfd::AsyncFileDialog::new()
+                .add_filter("Markdown", &["md", "markdown"])
+                .pick_file()
+                .await
+            {
+                Some(handle) => handle.path().to_path_buf(),
+                None => return,
+            },
+        };
+
+        match State::from_file(&path) {
+            Ok(state) => {
+                // Let queued edits land before the document is swapped out
+                dom_updates.idle().await;
+                visual_editor.set(state.raw_text.clone());
+                editor.write().replace_state(state);
+            }
+            Err(e) => {
+                eprintln!("Failed to open {}: {}", path.display(), e);
+            }
         }
-    }
+    });
 
     Ok(())
 }
 
+// Handler for Ctrl+S / Ctrl+Shift+S and the toolbar "Save" buttons
+pub fn handle_save_file(
+    save_as: bool,
+    editor: &Signal<EditorBuilder>,
+) -> Result<(), &'static str> {
+    let mut editor = editor.clone();
+
+    spawn(async move {
+        let path = if save_as || editor.read().file_path.is_none() {
+            match rfd::AsyncFileDialog::new()
+                .add_filter("Markdown", &["md", "markdown"])
+                .set_file_name("untitled.md")
+                .save_file()
+                .await
+            {
+                Some(handle) => Some(handle.path().to_path_buf()),
+                None => return,
+            }
+        } else {
+            None
+        };
+
+        let result = editor.write().save_file(path);
+        if let Err(e) = result {
+            eprintln!("Failed to save file: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+// Handler for closing the window. The window only hides when it is closed
+// (see `main`), so it is shown again if the user keeps the unsaved edits.
+pub fn handle_close_window(editor: &Signal<EditorBuilder>, window: DesktopContext) {
+    let editor = editor.clone();
+
+    spawn(async move {
+        if confirm_discard_changes(&editor).await {
+            window.set_close_behavior(WindowCloseBehaviour::LastWindowExitsApp);
+            window.close();
+        } else {
+            window.set_visible(true);
+        }
+    });
+}
@@ -0,0 +1,259 @@
+// src/history.rs
+use crate::buffer::{Document, RowId};
+
+/// What kind of keystroke opened a history step. Consecutive `Insert` steps
+/// on the same row are coalesced into a single undo step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EditKind {
+    Insert,
+    Other,
+}
+
+/// A change to `raw_text`, stored as the operation that reverts it.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+pub enum InverseOp {
+    /// Put back the cells a row had before it was edited.
+    RestoreRow { row: usize, cells: Vec<String> },
+    /// Re-insert a row that was removed, under the id it had.
+    InsertRow { row: usize, id: RowId, cells: Vec<String> },
+    /// Remove a row that was inserted.
+    RemoveRow { row: usize },
+}
+
+impl InverseOp {
+    /// Applies the operation to `raw_text` and returns the operation that reverts it.
+    fn apply(&self, raw_text: &mut Document) -> Option<InverseOp> {
+        match self {
+            InverseOp::RestoreRow { row, cells } => {
+                let current = raw_text.set_row(*row, cells)?;
+                Some(InverseOp::RestoreRow { row: *row, cells: current })
+            }
+            InverseOp::InsertRow { row, id, cells } => {
+                let row = (*row).min(raw_text.len());
+                raw_text.insert_row_with_id(row, *id, cells);
+                Some(InverseOp::RemoveRow { row })
+            }
+            InverseOp::RemoveRow { row } => {
+                let id = raw_text.row_id(*row)?;
+                let cells = raw_text.remove_row(*row)?;
+                Some(InverseOp::InsertRow { row: *row, id, cells })
+            }
+        }
+    }
+}
+
+/// One undoable user action: the inverse ops in the order they were recorded,
+/// plus the caret before and after the action.
+#[derive(Debug, Clone)]
+pub struct HistoryStep {
+    kind: EditKind,
+    ops: Vec<InverseOp>,
+    caret_before: Option<(usize, usize, usize)>,
+    caret_after: Option<(usize, usize, usize)>,
+}
+
+/// Result of an undo or redo: the row operations that were applied, in
+/// order, for the view to replay, and where to put the caret.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Restored {
+    pub changes: Vec<InverseOp>,
+    pub caret: Option<(usize, usize, usize)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    undo_stack: Vec<HistoryStep>,
+    redo_stack: Vec<HistoryStep>,
+
+    /// `true` while edits belong to the step on top of `undo_stack`. Stays on after
+    /// the handler's own ops so the syntax pass that follows lands in the same step.
+    recording: bool,
+
+    /// `false` once something other than typing happened, so the next insert starts a new step.
+    can_coalesce: bool,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens a new step for the edit that is about to be applied. Inserts on the
+    /// same row as the previous insert step are merged into that step instead.
+    pub fn begin_step(&mut self, kind: EditKind, caret: Option<(usize, usize, usize)>) {
+        // Drop a previous step that never recorded anything
+        if self.undo_stack.last().is_some_and(|step| step.ops.is_empty()) {
+            self.undo_stack.pop();
+        }
+
+        let coalesce = kind == EditKind::Insert
+            && self.can_coalesce
+            && self.undo_stack.last().is_some_and(|step| {
+                step.kind == EditKind::Insert
+                    && step.caret_before.map(|c| c.0) == caret.map(|c| c.0)
+            });
+
+        if !coalesce {
+            self.undo_stack.push(HistoryStep {
+                kind,
+                ops: Vec::new(),
+                caret_before: caret,
+                caret_after: None,
+            });
+        }
+
+        self.recording = true;
+        self.can_coalesce = kind == EditKind::Insert;
+    }
+
+    /// Makes sure the next insert opens a new step (caret moved, focus changed, ...).
+    pub fn break_coalescing(&mut self) {
+        self.can_coalesce = false;
+    }
+
+    /// Records the inverse of an edit that is about to be applied.
+    pub fn record(&mut self, op: InverseOp) {
+        if !self.recording {
+            return;
+        }
+        if let Some(step) = self.undo_stack.last_mut() {
+            // Typing into the same row only needs the row as it was before the first key
+            let already_saved = match (&op, step.ops.last()) {
+                (InverseOp::RestoreRow { row, .. }, Some(InverseOp::RestoreRow { row: last_row, .. })) => row == last_row,
+                _ => false,
+            };
+            if !already_saved {
+                step.ops.push(op);
+            }
+            self.redo_stack.clear();
+        }
+    }
+
+    #[cfg(test)]
+    pub fn can_undo(&self) -> bool {
+        self.undo_stack.iter().any(|step| !step.ops.is_empty())
+    }
+
+    #[cfg(test)]
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    /// Reverts the most recent step. `caret` is the current caret, remembered so redo can restore it.
+    pub fn undo(&mut self, raw_text: &mut Document, caret: Option<(usize, usize, usize)>) -> Option<Restored> {
+        self.recording = false;
+        self.can_coalesce = false;
+
+        let mut step = loop {
+            let step = self.undo_stack.pop()?;
+            if !step.ops.is_empty() {
+                break step;
+            }
+        };
+        if step.caret_after.is_none() {
+            step.caret_after = caret;
+        }
+
+        let (changes, redo_ops) = apply_all(step.ops.drain(..).rev(), raw_text);
+        let restored = Restored { changes, caret: step.caret_before };
+        self.redo_stack.push(HistoryStep { ops: redo_ops, ..step });
+        Some(restored)
+    }
+
+    /// Re-applies the most recently undone step.
+    pub fn redo(&mut self, raw_text: &mut Document) -> Option<Restored> {
+        self.recording = false;
+        self.can_coalesce = false;
+
+        let mut step = self.redo_stack.pop()?;
+
+        let (changes, undo_ops) = apply_all(step.ops.drain(..).rev(), raw_text);
+        let restored = Restored { changes, caret: step.caret_after };
+        self.undo_stack.push(HistoryStep { ops: undo_ops, ..step });
+        Some(restored)
+    }
+}
+
+/// Applies `ops` in order, skipping any that no longer fit the document.
+/// Returns the ops that were applied and the ops that revert them.
+fn apply_all(ops: impl Iterator<Item = InverseOp>, raw_text: &mut Document) -> (Vec<InverseOp>, Vec<InverseOp>) {
+    ops.filter_map(|op| {
+        let inverse = op.apply(raw_text)?;
+        Some((op, inverse))
+    })
+    .unzip()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(cells: &[&str]) -> Vec<String> {
+        cells.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_coalesced_inserts_undo_as_one_step() {
+        let mut history = History::new();
+        let mut raw_text = Document::from_rows([row(&["ab"])]);
+
+        for (caret, text) in [(2, "abc"), (3, "abcd")] {
+            history.begin_step(EditKind::Insert, Some((0, 0, caret)));
+            history.record(InverseOp::RestoreRow { row: 0, cells: raw_text.row(0).unwrap() });
+            raw_text.set_row(0, &row(&[text]));
+        }
+
+        let restored = history.undo(&mut raw_text, Some((0, 0, 4))).unwrap();
+        assert_eq!(raw_text.to_rows(), vec![row(&["ab"])]);
+        assert_eq!(restored.caret, Some((0, 0, 2)));
+        assert!(!history.can_undo());
+
+        let restored = history.redo(&mut raw_text).unwrap();
+        assert_eq!(raw_text.to_rows(), vec![row(&["abcd"])]);
+        assert_eq!(restored.caret, Some((0, 0, 4)));
+        assert_eq!(restored.changes, vec![InverseOp::RestoreRow { row: 0, cells: row(&["abcd"]) }]);
+    }
+
+    #[test]
+    fn test_undo_row_merge() {
+        let mut history = History::new();
+        let mut raw_text = Document::from_rows([row(&["# Title"]), row(&["plain ", "**bold**"])]);
+
+        // Backspace at the start of row 1 merges it into row 0
+        history.begin_step(EditKind::Other, Some((1, 0, 0)));
+        history.record(InverseOp::RestoreRow { row: 0, cells: raw_text.row(0).unwrap() });
+        let merged = raw_text.row(1).unwrap();
+        raw_text.update_row(0, |cells| cells.extend(merged));
+        let id = raw_text.row_id(1).unwrap();
+        let removed = raw_text.remove_row(1).unwrap();
+        history.record(InverseOp::InsertRow { row: 1, id, cells: removed });
+
+        let restored = history.undo(&mut raw_text, Some((0, 0, 7))).unwrap();
+        assert_eq!(raw_text.to_rows(), vec![row(&["# Title"]), row(&["plain ", "**bold**"])]);
+        assert_eq!(raw_text.row_id(1), Some(id));
+        assert_eq!(restored.changes, vec![
+            InverseOp::InsertRow { row: 1, id, cells: row(&["plain ", "**bold**"]) },
+            InverseOp::RestoreRow { row: 0, cells: row(&["# Title"]) },
+        ]);
+
+        history.redo(&mut raw_text).unwrap();
+        assert_eq!(raw_text.to_rows(), vec![row(&["# Title", "plain ", "**bold**"])]);
+    }
+
+    #[test]
+    fn test_new_edit_clears_redo() {
+        let mut history = History::new();
+        let mut raw_text = Document::from_rows([row(&["a"])]);
+
+        history.begin_step(EditKind::Other, None);
+        history.record(InverseOp::RemoveRow { row: 1 });
+        raw_text.push_row(&row(&[""]));
+        history.undo(&mut raw_text, None);
+        assert!(history.can_redo());
+
+        history.begin_step(EditKind::Insert, Some((0, 0, 1)));
+        history.record(InverseOp::RestoreRow { row: 0, cells: raw_text.row(0).unwrap() });
+        assert!(!history.can_redo());
+    }
+}
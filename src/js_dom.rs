@@ -0,0 +1,94 @@
+// src/js_dom.rs
+use dioxus::prelude::*;
+use base64::{engine::general_purpose, Engine as _};
+
+use crate::buffer::{Document, RowId};
+use crate::editor_core::{self, Dom};
+use crate::offset::TextOffset;
+
+/// The webview side of `EditorCore::apply`. Cells are rewritten through the
+/// functions in `assets/editor.js`; rows are added and removed by updating
+/// `visual_editor`, which the app renders from, keyed by `RowId`. The rows
+/// around an inserted or removed row keep their ids, so their elements are
+/// left as they are.
+///
+/// `apply` runs while the editor is borrowed mutably, so nothing here reads
+/// the editor: element ids come from `visual_editor`, which holds the same
+/// row ids, and scripts are sent from spawned tasks, in order.
+pub struct JsDom<F> {
+    visual_editor: Signal<Document>,
+    focus_element: F,
+}
+
+impl<F: Fn(usize, usize, usize)> JsDom<F> {
+    pub fn new(visual_editor: Signal<Document>, focus_element: F) -> Self {
+        Self { visual_editor, focus_element }
+    }
+
+    fn row_id(&self, row: usize) -> Option<RowId> {
+        self.visual_editor.peek().row_id(row)
+    }
+}
+
+fn run_js(js: String) {
+    spawn(async move {
+        let _ = document::eval(&js).await;
+    });
+}
+
+impl<F: Fn(usize, usize, usize)> Dom for JsDom<F> {
+    fn set_text(&mut self, row: usize, col: usize, text: &str, caret: Option<usize>) {
+        // A row the view does not have yet is drawn from `visual_editor` with its text
+        let Some(element_id) = self.row_id(row).map(|id| id.cell_element_id(col)) else {
+            return;
+        };
+        let text_b64 = general_purpose::STANDARD.encode(text);
+        let js = match caret {
+            // `caret` is a byte offset; the DOM wants UTF-16 code units
+            Some(caret) => format!(
+                r#"return window.clearElementTextWithPosition('{}', window.decodeBase64('{}'), {});"#,
+                element_id,
+                text_b64,
+                TextOffset::from_byte(text, caret).utf16(text)
+            ),
+            None => format!(
+                r#"return window.clearElementText('{}', window.decodeBase64('{}'));"#,
+                element_id,
+                text_b64
+            ),
+        };
+        run_js(js);
+    }
+
+    fn set_row(&mut self, row: usize, cells: &[String]) {
+        self.visual_editor.with_mut(|raw_text| {
+            raw_text.set_row(row, cells);
+        });
+        for (col, text) in cells.iter().enumerate() {
+            self.set_text(row, col, text, None);
+        }
+    }
+
+    fn insert_row(&mut self, row: usize, id: RowId, cells: &[String]) {
+        self.visual_editor.with_mut(|raw_text| raw_text.insert_row_with_id(row, id, cells));
+    }
+
+    fn remove_row(&mut self, row: usize) {
+        self.visual_editor.with_mut(|raw_text| {
+            raw_text.remove_row(row);
+        });
+    }
+
+    fn delete_cell(&mut self, row: usize, col: usize) {
+        if let Some(id) = self.row_id(row) {
+            run_js(format!(r#"return window.deleteElement('{}');"#, id.cell_element_id(col)));
+        }
+        self.visual_editor.with_mut(|raw_text| {
+            raw_text.update_row(row, |cells| editor_core::remove_cell(cells, col));
+        });
+    }
+
+    fn focus(&mut self, row: usize, col: usize, caret: usize) {
+        (self.focus_element)(row, col, caret);
+    }
+}
@@ -0,0 +1,113 @@
+// src/offset.rs
+use unicode_segmentation::UnicodeSegmentation;
+
+/// A caret offset inside the text of one cell.
+///
+/// The editor slices cell text by byte offsets, while the DOM reports and
+/// expects UTF-16 code units. The caret should also never stop inside a
+/// grapheme cluster, so `é` written as `e` + accent, or an emoji with a skin
+/// tone, is stepped over and deleted as one character. A `TextOffset` is a
+/// byte offset that always sits on a grapheme boundary of the text it was
+/// made from, and converts to and from the other two units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct TextOffset(usize);
+
+/// Byte offsets of every grapheme boundary in `text`, including `0` and `text.len()`.
+fn boundaries(text: &str) -> impl Iterator<Item = usize> + '_ {
+    text.grapheme_indices(true)
+        .map(|(idx, _)| idx)
+        .chain(std::iter::once(text.len()))
+}
+
+impl TextOffset {
+    /// A byte offset, clamped to `text` and moved back onto a grapheme boundary.
+    pub fn from_byte(text: &str, byte: usize) -> Self {
+        let byte = byte.min(text.len());
+        Self(boundaries(text).take_while(|&idx| idx <= byte).last().unwrap_or(0))
+    }
+
+    /// A UTF-16 offset as reported by the DOM (`Range.startOffset`).
+    pub fn from_utf16(text: &str, units: usize) -> Self {
+        let mut counted = 0;
+        let byte = text
+            .char_indices()
+            .find(|(_, c)| {
+                counted += c.len_utf16();
+                counted > units
+            })
+            .map(|(idx, _)| idx)
+            .unwrap_or(text.len());
+        Self::from_byte(text, byte)
+    }
+
+    /// The offset after the first `graphemes` user-perceived characters.
+    pub fn from_grapheme(text: &str, graphemes: usize) -> Self {
+        Self(boundaries(text).nth(graphemes).unwrap_or(text.len()))
+    }
+
+    pub fn byte(self) -> usize {
+        self.0
+    }
+
+    /// The same offset in UTF-16 code units, for the DOM.
+    pub fn utf16(self, text: &str) -> usize {
+        text[..self.0.min(text.len())].encode_utf16().count()
+    }
+
+    /// How many grapheme clusters come before the offset.
+    pub fn grapheme(self, text: &str) -> usize {
+        text[..self.0.min(text.len())].graphemes(true).count()
+    }
+
+    /// The boundary one grapheme to the left, or the start of the text.
+    pub fn prev(self, text: &str) -> Self {
+        Self(boundaries(text).take_while(|&idx| idx < self.0).last().unwrap_or(0))
+    }
+
+    /// The boundary one grapheme to the right, or the end of the text.
+    pub fn next(self, text: &str) -> Self {
+        Self(boundaries(text).find(|&idx| idx > self.0).unwrap_or(text.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offsets_convert_between_units() {
+        // "a", "é" (2 bytes), "👍🏽" (8 bytes, 4 UTF-16 units), "b"
+        let text = "aé👍🏽b";
+
+        let after_emoji = TextOffset::from_utf16(text, 6);
+        assert_eq!(after_emoji.byte(), 11);
+        assert_eq!(after_emoji.utf16(text), 6);
+        assert_eq!(after_emoji.grapheme(text), 3);
+        assert_eq!(TextOffset::from_grapheme(text, 3), after_emoji);
+        assert_eq!(TextOffset::from_grapheme(text, 10).byte(), text.len());
+    }
+
+    #[test]
+    fn test_offsets_snap_to_grapheme_boundaries() {
+        let text = "aé👍🏽b";
+
+        // Inside "é", inside the emoji and between the emoji and its skin tone
+        assert_eq!(TextOffset::from_byte(text, 2).byte(), 1);
+        assert_eq!(TextOffset::from_byte(text, 5).byte(), 3);
+        assert_eq!(TextOffset::from_utf16(text, 4).byte(), 3);
+        assert_eq!(TextOffset::from_byte(text, 99).byte(), text.len());
+        assert_eq!(TextOffset::from_byte("", 3).byte(), 0);
+    }
+
+    #[test]
+    fn test_steps_move_by_grapheme() {
+        let text = "aé👍🏽b";
+        let end = TextOffset::from_byte(text, text.len());
+
+        assert_eq!(end.prev(text).byte(), 11);
+        assert_eq!(end.prev(text).prev(text).byte(), 3);
+        assert_eq!(TextOffset::from_byte(text, 1).next(text).byte(), 3);
+        assert_eq!(TextOffset::default().prev(text).byte(), 0);
+        assert_eq!(end.next(text), end);
+    }
+}
@@ -0,0 +1,311 @@
+// src/selection.rs
+use std::ops::Range;
+
+use crate::buffer::Document;
+use crate::offset::TextOffset;
+
+/// A caret position: (row, cell, byte offset inside the cell).
+pub type Position = (usize, usize, usize);
+
+/// A text selection that can span cells and rows. The anchor stays where the
+/// selection started; the head follows the caret as the selection is extended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Selection {
+    pub anchor: Position,
+    pub head: Position,
+}
+
+impl Selection {
+    pub fn new(anchor: Position, head: Position) -> Self {
+        Self { anchor, head }
+    }
+
+    pub fn is_collapsed(&self) -> bool {
+        self.anchor == self.head
+    }
+
+    /// The anchor and head in document order.
+    pub fn ordered(&self) -> (Position, Position) {
+        if self.anchor <= self.head {
+            (self.anchor, self.head)
+        } else {
+            (self.head, self.anchor)
+        }
+    }
+}
+
+/// Offset of a caret position from the start of its row, across all cells.
+pub fn row_offset(row: &[String], index_j: usize, pos: usize) -> usize {
+    row.iter().take(index_j).map(|text| text.len()).sum::<usize>() + pos
+}
+
+/// The caret position for a row-level offset. An offset on the boundary of two
+/// cells stays at the end of the earlier cell.
+pub fn position_at(row: &[String], index_i: usize, offset: usize) -> Position {
+    let mut remaining = offset;
+    for (index_j, text) in row.iter().enumerate() {
+        if remaining <= text.len() {
+            return (index_i, index_j, remaining);
+        }
+        remaining -= text.len();
+    }
+    let last_j = row.len().saturating_sub(1);
+    (index_i, last_j, row.get(last_j).map(|text| text.len()).unwrap_or(0))
+}
+
+fn row_text(raw_text: &Document, index_i: usize) -> String {
+    raw_text.line(index_i).unwrap_or_default()
+}
+
+/// One character to the left, moving to the end of the previous row at a row start.
+pub fn step_left(raw_text: &Document, (index_i, index_j, pos): Position) -> Position {
+    let Some(row) = raw_text.row(index_i) else {
+        return (index_i, index_j, pos);
+    };
+    let offset = row_offset(&row, index_j, pos);
+    if offset == 0 {
+        return if index_i > 0 { row_end(raw_text, index_i - 1) } else { (index_i, 0, 0) };
+    }
+    let text = row.concat();
+    let prev = TextOffset::from_byte(&text, offset).prev(&text).byte();
+    position_at(&row, index_i, prev)
+}
+
+/// One character to the right, moving to the start of the next row at a row end.
+pub fn step_right(raw_text: &Document, (index_i, index_j, pos): Position) -> Position {
+    let Some(row) = raw_text.row(index_i) else {
+        return (index_i, index_j, pos);
+    };
+    let offset = row_offset(&row, index_j, pos);
+    let text = row.concat();
+    if offset >= text.len() {
+        return if index_i + 1 < raw_text.len() { (index_i + 1, 0, 0) } else { row_end(raw_text, index_i) };
+    }
+    let next = TextOffset::from_byte(&text, offset).next(&text).byte();
+    position_at(&row, index_i, next)
+}
+
+/// The same number of characters into the previous row, clamped to its length.
+pub fn step_up(raw_text: &Document, position: Position) -> Position {
+    if position.0 == 0 {
+        return (0, 0, 0);
+    }
+    move_to_row(raw_text, position, position.0 - 1)
+}
+
+/// The same number of characters into the next row, clamped to its length.
+pub fn step_down(raw_text: &Document, position: Position) -> Position {
+    if position.0 + 1 >= raw_text.len() {
+        return row_end(raw_text, position.0);
+    }
+    move_to_row(raw_text, position, position.0 + 1)
+}
+
+/// `rows` rows up, keeping the column; stops at the first row.
+pub fn page_up(raw_text: &Document, position: Position, rows: usize) -> Position {
+    move_to_row(raw_text, position, position.0.saturating_sub(rows))
+}
+
+/// `rows` rows down, keeping the column; stops at the last row.
+pub fn page_down(raw_text: &Document, position: Position, rows: usize) -> Position {
+    move_to_row(raw_text, position, position.0 + rows)
+}
+
+/// Moves to the same column on `target_i`, clamped to the document and the row.
+/// The column is counted in graphemes, so it lines up on rows with multi-byte text.
+pub fn move_to_row(raw_text: &Document, (index_i, index_j, pos): Position, target_i: usize) -> Position {
+    if raw_text.is_empty() {
+        return (0, 0, 0);
+    }
+    let target_i = target_i.min(raw_text.len() - 1);
+    let offset = raw_text.row(index_i).map(|row| row_offset(&row, index_j, pos)).unwrap_or(0);
+    let source = row_text(raw_text, index_i);
+    let column = TextOffset::from_byte(&source, offset).grapheme(&source);
+    let target = row_text(raw_text, target_i);
+    let offset = TextOffset::from_grapheme(&target, column).byte();
+    position_at(&raw_text.row(target_i).unwrap_or_default(), target_i, offset)
+}
+
+/// What a character counts as when moving by word. Emphasis and code markers
+/// are word characters, so `**bold**` moves as one word.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum CharClass {
+    Space,
+    Word,
+    Punctuation,
+}
+
+impl CharClass {
+    fn of(c: char) -> Self {
+        if c.is_whitespace() {
+            CharClass::Space
+        } else if c.is_alphanumeric() || matches!(c, '*' | '_' | '~' | '`') {
+            CharClass::Word
+        } else {
+            CharClass::Punctuation
+        }
+    }
+}
+
+/// Iterates over the words of a row's text: byte ranges of runs of word or
+/// punctuation characters, skipping whitespace.
+pub fn words(text: &str) -> impl Iterator<Item = Range<usize>> + '_ {
+    let mut chars = text.char_indices().peekable();
+    std::iter::from_fn(move || {
+        let (start, class) = loop {
+            let (idx, c) = chars.next()?;
+            let class = CharClass::of(c);
+            if class != CharClass::Space {
+                break (idx, class);
+            }
+        };
+        let mut end = text.len();
+        while let Some(&(idx, c)) = chars.peek() {
+            if CharClass::of(c) != class {
+                end = idx;
+                break;
+            }
+            chars.next();
+        }
+        Some(start..end)
+    })
+}
+
+/// Ctrl+Left: the start of the word before the caret, or the end of the previous row.
+pub fn word_left(raw_text: &Document, (index_i, index_j, pos): Position) -> Position {
+    let Some(row) = raw_text.row(index_i) else {
+        return (index_i, index_j, pos);
+    };
+    let offset = row_offset(&row, index_j, pos);
+    if offset == 0 {
+        return step_left(raw_text, (index_i, index_j, pos));
+    }
+    let text = row.concat();
+    let start = words(&text)
+        .take_while(|word| word.start < offset)
+        .last()
+        .map(|word| word.start)
+        .unwrap_or(0);
+    position_at(&row, index_i, start)
+}
+
+/// Ctrl+Right: the end of the word after the caret, or the start of the next row.
+pub fn word_right(raw_text: &Document, (index_i, index_j, pos): Position) -> Position {
+    let Some(row) = raw_text.row(index_i) else {
+        return (index_i, index_j, pos);
+    };
+    let offset = row_offset(&row, index_j, pos);
+    let text = row.concat();
+    if offset >= text.len() {
+        return step_right(raw_text, (index_i, index_j, pos));
+    }
+    let end = words(&text)
+        .find(|word| word.end > offset)
+        .map(|word| word.end)
+        .unwrap_or(text.len());
+    position_at(&row, index_i, end)
+}
+
+pub fn row_start(index_i: usize) -> Position {
+    (index_i, 0, 0)
+}
+
+pub fn row_end(raw_text: &Document, index_i: usize) -> Position {
+    let row = raw_text.row(index_i).unwrap_or_default();
+    let last_j = row.len().saturating_sub(1);
+    (index_i, last_j, row.get(last_j).map(|text| text.len()).unwrap_or(0))
+}
+
+pub fn document_start() -> Position {
+    (0, 0, 0)
+}
+
+/// The end of the last row with text, ignoring the empty rows padding the document.
+pub fn document_end(raw_text: &Document) -> Position {
+    let last_i = (0..raw_text.len())
+        .rev()
+        .find(|&index_i| raw_text.line(index_i).is_some_and(|line| !line.is_empty()))
+        .unwrap_or(0);
+    row_end(raw_text, last_i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc(rows: &[&[&str]]) -> Document {
+        Document::from_rows(rows.iter().map(|row| row.iter().map(|s| s.to_string()).collect::<Vec<_>>()))
+    }
+
+    #[test]
+    fn test_steps_cross_cells_and_rows() {
+        let raw_text = doc(&[&["ab", "**c**"], &["é"]]);
+
+        assert_eq!(step_right(&raw_text, (0, 0, 1)), (0, 0, 2));
+        assert_eq!(step_right(&raw_text, (0, 0, 2)), (0, 1, 1));
+        assert_eq!(step_right(&raw_text, (0, 1, 5)), (1, 0, 0));
+        assert_eq!(step_right(&raw_text, (1, 0, 0)), (1, 0, 2));
+
+        assert_eq!(step_left(&raw_text, (1, 0, 2)), (1, 0, 0));
+        assert_eq!(step_left(&raw_text, (1, 0, 0)), (0, 1, 5));
+        assert_eq!(step_left(&raw_text, (0, 1, 0)), (0, 0, 1));
+        assert_eq!(step_left(&raw_text, (0, 0, 0)), (0, 0, 0));
+
+        // "e" plus a combining accent is one character
+        let combining = doc(&[&["e\u{301}!"]]);
+        assert_eq!(step_right(&combining, (0, 0, 0)), (0, 0, 3));
+        assert_eq!(step_left(&combining, (0, 0, 3)), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_vertical_steps_keep_row_offset() {
+        let raw_text = doc(&[&["abc", "def"], &["é"], &["ghijkl"]]);
+
+        assert_eq!(step_down(&raw_text, (0, 1, 1)), (1, 0, 2));
+        assert_eq!(step_up(&raw_text, (2, 0, 1)), (1, 0, 2));
+        assert_eq!(step_up(&raw_text, (2, 0, 4)), (1, 0, 2));
+        assert_eq!(step_down(&raw_text, (2, 0, 1)), (2, 0, 6));
+        assert_eq!(row_end(&raw_text, 0), (0, 1, 3));
+    }
+
+    #[test]
+    fn test_page_and_document_jumps() {
+        let raw_text = doc(&[&["first"], &["ab"], &["third row"], &[""], &[""]]);
+
+        assert_eq!(page_down(&raw_text, (0, 0, 4), 2), (2, 0, 4));
+        assert_eq!(page_down(&raw_text, (0, 0, 4), 10), (4, 0, 0));
+        assert_eq!(page_up(&raw_text, (2, 0, 4), 10), (0, 0, 4));
+        assert_eq!(move_to_row(&raw_text, (2, 0, 9), 1), (1, 0, 2));
+        assert_eq!(document_end(&raw_text), (2, 0, 9));
+    }
+
+    #[test]
+    fn test_words_skip_spaces_and_keep_markers() {
+        let text = "say **bold** text, ok";
+        let found: Vec<&str> = words(text).map(|word| &text[word]).collect();
+
+        assert_eq!(found, vec!["say", "**bold**", "text", ",", "ok"]);
+    }
+
+    #[test]
+    fn test_word_moves_cross_cells() {
+        // "**bold**" straddles the PlainText and BoldText cells
+        let raw_text = doc(&[&["say ", "**bold**", " text"], &["next"]]);
+
+        assert_eq!(word_right(&raw_text, (0, 0, 0)), (0, 0, 3));
+        assert_eq!(word_right(&raw_text, (0, 0, 3)), (0, 1, 8));
+        assert_eq!(word_right(&raw_text, (0, 2, 5)), (1, 0, 0));
+
+        assert_eq!(word_left(&raw_text, (0, 2, 5)), (0, 2, 1));
+        assert_eq!(word_left(&raw_text, (0, 2, 1)), (0, 0, 4));
+        assert_eq!(word_left(&raw_text, (1, 0, 0)), (0, 2, 5));
+    }
+
+    #[test]
+    fn test_selection_order() {
+        let selection = Selection::new((2, 0, 1), (0, 1, 3));
+
+        assert!(!selection.is_collapsed());
+        assert_eq!(selection.ordered(), ((0, 1, 3), (2, 0, 1)));
+    }
+}
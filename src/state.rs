@@ -1,24 +1,68 @@
-use crate::handler;
-
 // src/state.rs
-use dioxus::prelude::*;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
-use crate::syntax::text::{TextProcessor, MarkDownElements};
-use crate::syntax::markdown::{MarkDownStyle};
+use crate::buffer::{Document, RowId};
+use crate::font;
+use crate::history::{EditKind, History, InverseOp, Restored};
+use crate::offset::TextOffset;
+use crate::selection::{Position, Selection};
+use crate::syntax::block::LineBlock;
+use crate::syntax::table::TableRowKind;
+use crate::syntax::text::{CodeLine, MarkDownElements, ParsedDocument, RowPatch, TextProcessor};
+use crate::syntax::markdown::{compute_markdown_style_props, CellInfo, MarkDownStyle};
+use crate::viewport;
 
 
 /// Holds all “global” editor signals and the caret coroutine.
 /// Each `Signal<T>` is a reactive state that can be read or written.
 #[derive(Clone)]
 pub struct State {
-    /// The main text buffer (e.g., Markdown, plain text, etc.)
-    pub raw_text: Vec<Vec<String>>,
+    /// The main text buffer: the cells of each row, see `Document`.
+    pub raw_text: Document,
 
     /// Where the caret (cursor) is, if anywhere. `None` means no focus.
     pub caret_pos: Option<(usize, usize, usize)>,
 
     pub text_width: Vec<Vec<Option<f64>>>,
 
+    /// The file this document was loaded from or last saved to, if any.
+    pub file_path: Option<PathBuf>,
+
+    /// `true` when `raw_text` has edits that have not been written to `file_path`.
+    pub dirty: bool,
+
+    /// Undo/redo stacks of inverse operations.
+    pub history: History,
+
+    /// The selected text, if any. The head always matches `caret_pos`.
+    pub selection: Option<Selection>,
+
+    /// While an input method is composing, the range its text will replace:
+    /// the caret, or the selection, as it was when the composition started.
+    pub composition: Option<Selection>,
+
+    /// The parse of `raw_text`, updated row by row by `reparse`.
+    parsed: ParsedDocument,
+
+    /// The estimated height of each row of `parsed`, see `viewport::row_height`.
+    row_heights: Vec<f64>,
+
+    /// Whether the source ended every line with `"\r\n"`. Rows hold the lines
+    /// without the `'\r'`; `to_markdown` puts it back.
+    crlf: bool,
+
+    /// How many empty rows the source ends with. `new` pads the document
+    /// with more, so `to_markdown` drops every trailing empty row and puts
+    /// back this many.
+    trailing_blank_rows: usize,
+
+    /// The line of each row as it was loaded, so that saving re-pads only
+    /// the tables that were edited.
+    loaded_lines: HashMap<RowId, String>,
 
 }
 
@@ -30,49 +74,412 @@ impl State {
         caret_pos: Option<(usize, usize, usize)>
     ) -> Self {
         
+        // A file that mixes line endings keeps its '\r's in the rows instead
+        let crlf = input_text.contains("\r\n") && input_text.matches('\n').count() == input_text.matches("\r\n").count();
+        let input_text = if crlf { input_text.replace("\r\n", "\n") } else { input_text };
+
         let text_processor = TextProcessor::new();
         let syntax_text = text_processor.process_markdown(input_text);
-        let raw_text = text_processor.extract_strings(syntax_text);
-        let mut raw_text = raw_text.clone();
+        let mut raw_text = Document::from_rows(text_processor.extract_strings(syntax_text));
+        let trailing_blank_rows = (0..raw_text.len()).rev()
+            .take_while(|&index_i| raw_text.line(index_i).is_some_and(|line| line.is_empty()))
+            .count();
+        let loaded_lines = (0..raw_text.len())
+            .filter_map(|index_i| Some((raw_text.row_id(index_i)?, raw_text.line(index_i)?)))
+            .collect();
         while raw_text.len() < 20 {
-            raw_text.push(vec![String::new()]);
+            raw_text.push_row(&[String::new()]);
         }
 
-        let text_width: Vec<Vec<Option<f64>>> = raw_text.iter()
-            .map(|inner| vec![None; inner.len()])
+        let text_width: Vec<Vec<Option<f64>>> = (0..raw_text.len())
+            .map(|index_i| vec![None; raw_text.row_len(index_i)])
             .collect();
 
         // let selection_range = None::<Vec<(usize, usize)>>;
         println!("{:?}", "New State");
-        Self {
+        let mut state = Self {
             raw_text,
             caret_pos,
             text_width,
+            file_path: None,
+            dirty: false,
+            history: History::new(),
+            selection: None,
+            composition: None,
+            parsed: ParsedDocument::default(),
+            row_heights: Vec::new(),
+            crlf,
+            trailing_blank_rows,
+            loaded_lines,
             // selection_range,
+        };
+        // Widths are measured as rows are rendered
+        state.reparse();
+        state
+    }
+
+    /// Loads a Markdown file from disk. A path that does not exist yet opens
+    /// as an empty document that will be created on the first save.
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let input_text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut state = Self::new(input_text, None);
+        state.file_path = Some(path.to_path_buf());
+        Ok(state)
+    }
+
+    /// Joins the cell grid back into Markdown source, dropping the empty
+    /// padding rows that `new` appends below the document. The columns of
+    /// edited tables are re-padded so the saved source stays aligned; the
+    /// rest is written as it was loaded, so an untouched document saves
+    /// byte for byte the same.
+    pub fn to_markdown(&self) -> String {
+        let text_processor = TextProcessor::new();
+        let mut rows = self.raw_text.to_rows();
+        while rows.last().is_some_and(|row| row.iter().all(|cell| cell.is_empty())) {
+            rows.pop();
+        }
+        rows.extend(std::iter::repeat_with(|| vec![String::new()]).take(self.trailing_blank_rows));
+
+        let text = text_processor.align_tables_where(
+            &text_processor.markdown_to_string(rows),
+            |table| table.clone().any(|index_i| self.row_edited(index_i)),
+        );
+        if self.crlf {
+            text.replace('\n', "\r\n")
+        } else {
+            text
         }
     }
 
+    /// Whether row `index_i` differs from the line it was loaded with.
+    fn row_edited(&self, index_i: usize) -> bool {
+        let loaded = self.raw_text.row_id(index_i).and_then(|id| self.loaded_lines.get(&id));
+        loaded != self.raw_text.line(index_i).as_ref()
+    }
+
+    /// Writes the document to `path` (or the current `file_path` when `None`)
+    /// and clears the dirty flag.
+    pub fn save(&mut self, path: Option<PathBuf>) -> io::Result<()> {
+        let path = match path.or_else(|| self.file_path.clone()) {
+            Some(path) => path,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no file path to save to")),
+        };
+
+        fs::write(&path, self.to_markdown())?;
+        self.file_path = Some(path);
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Flags the document as having unsaved edits.
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
     /// Updates the text at the given indices and adjusts caret position if needed.
     pub fn update_text(&mut self, index_i: usize, index_j: usize, text: String) {
-        while index_i >= self.raw_text.len() {
-            self.raw_text.push(Vec::new());
-        }
-        while index_j >= self.raw_text[index_i].len() {
-            self.raw_text[index_i].push("".to_string());
+        if self.raw_text.cell(index_i, index_j).unwrap_or_default() != text {
+            self.record_row(index_i);
+            self.dirty = true;
         }
-        self.raw_text[index_i][index_j] = text;
+        // Adds the missing rows and cells up to this one
+        self.raw_text.set_cell(index_i, index_j, &text);
     
         // Adjust caret position if it exists and is in the modified div
         if let Some((caret_i, caret_j, caret_offset)) = self.caret_pos {
             if caret_i == index_i && caret_j == index_j {
-                self.caret_pos = Some((caret_i, caret_j, caret_offset.min(self.raw_text[index_i][index_j].len())));
+                let caret_offset = TextOffset::from_byte(&text, caret_offset).byte();
+                self.caret_pos = Some((caret_i, caret_j, caret_offset));
             }
         }
     }
 
-    /// Moves the caret to a new position, ensuring it's within valid bounds.
+    /// Returns the text of a cell, or an empty string if it does not exist.
+    pub fn cell_text(&self, index_i: usize, index_j: usize) -> String {
+        self.raw_text.cell(index_i, index_j).unwrap_or_default()
+    }
+
+    /// Brings `parsed` up to date with `raw_text`, reparsing only around the
+    /// rows edited since the last call, and returns the rows whose parse
+    /// changed. Their widths are left to be measured again by `measure_text_widths`.
+    pub fn reparse(&mut self) -> RowPatch {
+        let Some(edited) = self.raw_text.take_edited() else {
+            return RowPatch::default();
+        };
+        let old_len = self.parsed.rows().len();
+        let patch = self.parsed.update(&TextProcessor::new(), &self.raw_text, edited);
+
+        // Row edits resize `text_width` to `raw_text`; line it up with the parse the patch applies to
+        self.text_width.resize(old_len, Vec::new());
+        self.text_width.splice(patch.start..patch.start + patch.removed, vec![Vec::new(); patch.rows.len()]);
+        self.row_heights.splice(patch.start..patch.start + patch.removed, patch.rows.iter().map(|row| viewport::row_height(row)));
+        patch
+    }
+
+    /// The parse of the document as of the last `reparse`, one row per line.
+    pub fn parsed_rows(&self) -> &[Vec<MarkDownElements>] {
+        self.parsed.rows()
+    }
+
+    /// The estimated height in px of each row of `parsed_rows`.
+    pub fn row_heights(&self) -> &[f64] {
+        &self.row_heights
+    }
+
+    /// Fills `text_width` for the rows in `rows` that have not been measured
+    /// since they last changed, from the bundled font at the size each cell is
+    /// drawn with. Returns the cells the font cannot measure, as row, column,
+    /// text and font size, for the webview to measure instead.
+    pub fn measure_text_widths(&mut self, rows: Range<usize>) -> Vec<(usize, usize, String, usize)> {
+        let mut unmeasured = Vec::new();
+        let len = self.text_width.len().min(self.parsed.rows().len());
+        let rows = rows.start.min(len)..rows.end.min(len);
+        for index_i in rows {
+            let row = &self.parsed.rows()[index_i];
+            if !self.text_width[index_i].is_empty() || row.is_empty() {
+                continue;
+            }
+            self.text_width[index_i] = row.iter().enumerate()
+                .map(|(index_j, syntax)| {
+                    let style = compute_markdown_style_props(CellInfo { row: index_i, col: index_j, num_cols: row.len(), width: None, syntax: syntax.clone() });
+                    if style.text.is_empty() {
+                        return None;
+                    }
+                    let width = font::text_width(&style.text, style.font_size);
+                    if width.is_none() {
+                        unmeasured.push((index_i, index_j, style.text, style.font_size));
+                    }
+                    width
+                })
+                .collect();
+        }
+        unmeasured
+    }
+
+    /// Saves the current cells of a row in the open history step before it is modified in place.
+    pub fn record_row(&mut self, index_i: usize) {
+        if let Some(cells) = self.raw_text.row(index_i) {
+            self.history.record(InverseOp::RestoreRow { row: index_i, cells });
+        }
+    }
+
+    /// Inserts a new row at `index_i`, recording it for undo. Returns its id.
+    pub fn insert_row(&mut self, index_i: usize, cells: Vec<String>) -> RowId {
+        let index_i = index_i.min(self.raw_text.len());
+        self.history.record(InverseOp::RemoveRow { row: index_i });
+        self.dirty = true;
+        self.raw_text.insert_row(index_i, &cells)
+    }
+
+    /// Removes the row at `index_i`, recording it for undo.
+    pub fn remove_row(&mut self, index_i: usize) -> Option<Vec<String>> {
+        let id = self.raw_text.row_id(index_i)?;
+        let cells = self.raw_text.remove_row(index_i)?;
+        self.history.record(InverseOp::InsertRow { row: index_i, id, cells: cells.clone() });
+        self.dirty = true;
+        Some(cells)
+    }
+
+    /// The webview's id for the element of a cell, made from its row's `RowId`.
+    pub fn element_id(&self, index_i: usize, index_j: usize) -> Option<String> {
+        self.raw_text.row_id(index_i).map(|id| id.cell_element_id(index_j))
+    }
+
+    /// The position of a cell the webview reports by its row's id.
+    pub fn row_index(&self, id: RowId) -> Option<usize> {
+        self.raw_text.row_index(id)
+    }
+
+    /// Returns whether row `index_i` is plain Markdown, a code fence or code
+    /// block content, as of the last `reparse`.
+    pub fn line_block(&self, index_i: usize) -> LineBlock {
+        match self.parsed.rows().get(index_i).and_then(|row| row.first()) {
+            Some(MarkDownElements::CodeBlock(CodeLine { language, fence: true, .. })) => LineBlock::CodeFence(language.clone()),
+            Some(MarkDownElements::CodeBlock(CodeLine { language, .. })) => LineBlock::Code(language.clone()),
+            _ => LineBlock::Normal,
+        }
+    }
+
+    /// Clamps a caret position to an existing cell and offset.
+    fn clamp_position(&self, (index_i, index_j, char_pos): Position) -> Position {
+        let index_i = index_i.min(self.raw_text.len().saturating_sub(1));
+        let row = self.raw_text.row(index_i).unwrap_or_default();
+        let index_j = index_j.min(row.len().saturating_sub(1));
+        let text = row.get(index_j).map(|text| text.as_str()).unwrap_or("");
+        (index_i, index_j, TextOffset::from_byte(text, char_pos).byte())
+    }
+
+    /// Puts two caret positions in document order and clamps them to the text.
+    fn ordered_range(&self, a: Position, b: Position) -> (Position, Position) {
+        let (a, b) = (self.clamp_position(a), self.clamp_position(b));
+        if a <= b { (a, b) } else { (b, a) }
+    }
+
+    /// The cells between two caret positions, cut at the offsets, as rows of cells.
+    pub fn text_in_range(&self, a: Position, b: Position) -> Vec<Vec<String>> {
+        if self.raw_text.is_empty() {
+            return Vec::new();
+        }
+        let ((start_i, start_j, start_pos), (end_i, end_j, end_pos)) = self.ordered_range(a, b);
+
+        (start_i..=end_i)
+            .map(|index_i| {
+                let row = self.raw_text.row(index_i).unwrap_or_default();
+                let first_j = if index_i == start_i { start_j } else { 0 };
+                let last_j = if index_i == end_i { end_j } else { row.len().saturating_sub(1) };
+                (first_j..=last_j.min(row.len().saturating_sub(1)))
+                    .filter_map(|index_j| {
+                        let text = row.get(index_j)?;
+                        let from = if (index_i, index_j) == (start_i, start_j) { start_pos } else { 0 };
+                        let to = if (index_i, index_j) == (end_i, end_j) { end_pos } else { text.len() };
+                        Some(text[from..to.max(from)].to_string())
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Deletes the text between two caret positions, joining the first and last
+    /// row, and returns where the caret ends up. Recorded in the open history step.
+    pub fn delete_range(&mut self, a: Position, b: Position) -> Position {
+        if self.raw_text.is_empty() {
+            return (0, 0, 0);
+        }
+        let ((start_i, start_j, start_pos), (end_i, end_j, end_pos)) = self.ordered_range(a, b);
+
+        let start_row = self.raw_text.row(start_i).unwrap_or_default();
+        let end_row = self.raw_text.row(end_i).unwrap_or_default();
+        let mut row: Vec<String> = start_row[..start_j].to_vec();
+        let head = &start_row[start_j][..start_pos];
+        let tail = &end_row[end_j][end_pos..];
+        row.push(format!("{}{}", head, tail));
+        row.extend_from_slice(&end_row[end_j + 1..]);
+
+        self.record_row(start_i);
+        for index_i in (start_i + 1..=end_i).rev() {
+            self.remove_row(index_i);
+        }
+        self.raw_text.set_row(start_i, &row);
+        self.text_width.resize(self.raw_text.len(), vec![None]);
+        self.dirty = true;
+        self.selection = None;
+
+        self.move_caret(start_i, start_j, start_pos);
+        (start_i, start_j, start_pos)
+    }
+
+    /// Deletes the text between two caret positions and types `text` (a single
+    /// line) in its place. Returns the caret after the inserted text.
+    pub fn replace_range(&mut self, a: Position, b: Position, text: &str) -> Position {
+        let (index_i, index_j, char_pos) = self.delete_range(a, b);
+        if text.is_empty() || index_i >= self.raw_text.len() {
+            return (index_i, index_j, char_pos);
+        }
+        self.raw_text.update_row(index_i, |cells| cells[index_j].insert_str(char_pos, text));
+        self.move_caret(index_i, index_j, char_pos + text.len());
+        (index_i, index_j, char_pos + text.len())
+    }
+
+    /// The selection, unless it is empty.
+    pub fn active_selection(&self) -> Option<Selection> {
+        self.selection.filter(|selection| !selection.is_collapsed())
+    }
+
+    /// Selects from `anchor` to `head` and moves the caret to the head.
+    pub fn set_selection(&mut self, anchor: Position, head: Position) {
+        let (anchor, head) = (self.clamp_position(anchor), self.clamp_position(head));
+        self.selection = Some(Selection::new(anchor, head));
+        self.move_caret(head.0, head.1, head.2);
+    }
+
+    /// Moves the head of the selection, starting a new one at the caret if there is none.
+    pub fn extend_selection(&mut self, head: Position) {
+        let anchor = self.selection
+            .map(|selection| selection.anchor)
+            .or(self.caret_pos)
+            .unwrap_or(head);
+        self.set_selection(anchor, head);
+    }
+
+    pub fn clear_selection(&mut self) {
+        self.selection = None;
+    }
+
+    /// Starts an input method composition at the caret (or over the selection),
+    /// unless one is already running.
+    pub fn begin_composition(&mut self, fallback: Position) {
+        if self.composition.is_none() {
+            let caret = self.caret_pos.unwrap_or(fallback);
+            self.composition = Some(self.active_selection().unwrap_or(Selection::new(caret, caret)));
+        }
+    }
+
+    /// Ends the composition and returns the range the composed text replaces.
+    pub fn end_composition(&mut self) -> Option<Selection> {
+        self.composition.take()
+    }
+
+    /// Returns the rows of the table that row `index_i` belongs to, if any, as
+    /// of the last `reparse`. A table starts at its header row and runs over the
+    /// table rows that follow it.
+    pub fn table_at(&self, index_i: usize) -> Option<Range<usize>> {
+        let rows = self.parsed.rows();
+        let table_row = |index_i: usize| match rows.get(index_i).and_then(|row| row.first()) {
+            Some(MarkDownElements::TableCell(cell)) => Some(cell.kind),
+            _ => None,
+        };
+        table_row(index_i)?;
+
+        let start = (0..=index_i).rev().find(|&row| table_row(row) == Some(TableRowKind::Header))?;
+        let end = (index_i + 1..rows.len())
+            .find(|&row| table_row(row).is_none_or(|kind| kind == TableRowKind::Header))
+            .unwrap_or(rows.len());
+        Some(start..end)
+    }
+
+    /// Opens a history step for the edit about to be applied, see `History::begin_step`.
+    pub fn begin_history_step(&mut self, kind: EditKind) {
+        self.history.begin_step(kind, self.caret_pos);
+    }
+
+    /// Reverts the last edit and moves the caret back to where it was before it.
+    pub fn undo(&mut self) -> Option<Restored> {
+        let mut restored = self.history.undo(&mut self.raw_text, self.caret_pos)?;
+        self.after_history_change(&mut restored);
+        Some(restored)
+    }
+
+    /// Re-applies the last undone edit.
+    pub fn redo(&mut self) -> Option<Restored> {
+        let mut restored = self.history.redo(&mut self.raw_text)?;
+        self.after_history_change(&mut restored);
+        Some(restored)
+    }
+
+    fn after_history_change(&mut self, restored: &mut Restored) {
+        if self.raw_text.is_empty() {
+            let cells = vec![String::new()];
+            let id = self.raw_text.push_row(&cells);
+            restored.changes.push(InverseOp::InsertRow { row: 0, id, cells });
+        }
+        self.text_width.resize(self.raw_text.len(), vec![None]);
+        self.dirty = true;
+        match restored.caret {
+            Some((index_i, index_j, char_pos)) => self.move_caret(index_i, index_j, char_pos),
+            None => self.clear_caret(),
+        }
+    }
+
+    /// Moves the caret to a new position, ensuring it's within valid bounds and
+    /// on a grapheme boundary, so slicing the cell at the caret never panics.
     pub fn move_caret(&mut self, index_i: usize, index_j: usize, char_pos: usize) {
-        if index_i < self.raw_text.len() && index_j < self.raw_text[index_i].len() {
+        if let Some(text) = self.raw_text.cell(index_i, index_j) {
+            let char_pos = TextOffset::from_byte(&text, char_pos).byte();
             self.caret_pos = Some((index_i, index_j, char_pos));
         } else {
             self.caret_pos = None;
@@ -0,0 +1,162 @@
+use std::ops::Range;
+
+use pulldown_cmark::{CodeBlockKind, Event, Tag, TagEnd};
+
+use super::text::line_of;
+
+/// The block context of a single source line.
+#[derive(Debug, PartialEq, Clone)]
+pub enum LineBlock {
+    /// Regular Markdown, parsed for inline formatting.
+    Normal,
+    /// The opening or closing fence of a fenced code block, with the block's language.
+    CodeFence(Option<String>),
+    /// A line inside a fenced or indented code block.
+    Code(Option<String>),
+}
+
+impl LineBlock {
+    pub fn is_code(&self) -> bool {
+        !matches!(self, LineBlock::Normal)
+    }
+}
+
+/// A fenced or indented code block, as pulldown-cmark reports it.
+#[derive(Debug, PartialEq, Clone)]
+struct CodeBlock {
+    /// The whole block, fences included.
+    range: Range<usize>,
+    fenced: bool,
+    /// The first word of a fenced block's info string.
+    language: Option<String>,
+    /// Where the block's last piece of code ends, if it has any.
+    content_end: Option<usize>,
+}
+
+/// Collects the code blocks of a document from pulldown-cmark's offset
+/// iterator, so code rows are found with the same container rules (list
+/// items, block quotes) as the rest of the parse.
+#[derive(Debug, Default)]
+pub struct CodeBlocks {
+    blocks: Vec<CodeBlock>,
+    open: Option<CodeBlock>,
+}
+
+impl CodeBlocks {
+    /// Feeds the next event of the offset iterator.
+    pub fn feed(&mut self, event: &Event, range: &Range<usize>) {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let (fenced, language) = match kind {
+                    CodeBlockKind::Fenced(info) => (true, info.split_whitespace().next().map(|word| word.to_string())),
+                    CodeBlockKind::Indented => (false, None),
+                };
+                self.open = Some(CodeBlock { range: range.clone(), fenced, language, content_end: None });
+            }
+            Event::Text(_) => {
+                if let Some(block) = &mut self.open {
+                    block.content_end = Some(range.end);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                self.blocks.extend(self.open.take());
+            }
+            _ => {}
+        }
+    }
+
+    /// Classifies the lines starting at `line_starts`, of a source `source_len`
+    /// bytes long. A fenced block's first line is its opening fence; its last
+    /// line is the closing fence unless it holds code, which means the block was
+    /// left open. A block left open at the end of the source takes in the blank
+    /// lines after it too, since whatever is typed there becomes code.
+    pub fn classify(&self, line_starts: &[usize], source_len: usize) -> Vec<LineBlock> {
+        let mut lines = vec![LineBlock::Normal; line_starts.len()];
+        for block in &self.blocks {
+            let first = line_of(line_starts, block.range.start);
+            let mut last = line_of(line_starts, block.range.end.saturating_sub(1).max(block.range.start));
+            if !block.fenced {
+                lines[first..=last].fill(LineBlock::Code(None));
+                continue;
+            }
+
+            let closed = last > first && block.content_end
+                .is_none_or(|end| line_of(line_starts, end.saturating_sub(1)) < last);
+            if !closed && block.range.end == source_len {
+                last = line_starts.len() - 1;
+            }
+            lines[first..=last].fill(LineBlock::Code(block.language.clone()));
+            lines[first] = LineBlock::CodeFence(block.language.clone());
+            if closed {
+                lines[last] = LineBlock::CodeFence(block.language.clone());
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::text::{line_starts, TextProcessor};
+    use pulldown_cmark::Parser;
+
+    /// Classifies every line of a document.
+    fn classify(source: &str) -> Vec<LineBlock> {
+        let mut code_blocks = CodeBlocks::default();
+        for (event, range) in Parser::new_ext(source, TextProcessor::parser_options()).into_offset_iter() {
+            code_blocks.feed(&event, &range);
+        }
+        code_blocks.classify(&line_starts(source), source.len())
+    }
+
+    #[test]
+    fn test_fenced_and_indented_code() {
+        let input = "text\n```rust\nlet a = **b**;\n\n```\n\n    indented\nafter\n~~~\nunclosed";
+        let result = classify(input);
+
+        let rust = Some("rust".to_string());
+        let expected = vec![
+            LineBlock::Normal,
+            LineBlock::CodeFence(rust.clone()),
+            LineBlock::Code(rust.clone()),
+            LineBlock::Code(rust.clone()),
+            LineBlock::CodeFence(rust),
+            LineBlock::Normal,
+            LineBlock::Code(None),
+            LineBlock::Normal,
+            LineBlock::CodeFence(None),
+            LineBlock::Code(None),
+        ];
+
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_indented_lines_that_are_not_code() {
+        // Paragraph continuation and list item content are not indented code
+        let input = "para\n    still para\n- item\n\n    item content";
+        let result = classify(input);
+
+        assert!(result.iter().all(|block| *block == LineBlock::Normal));
+    }
+
+    #[test]
+    fn test_fences_inside_containers() {
+        // A fence in a list item ends with the item; the unindented fence opens a new block
+        let result = classify("- a\n  ```\n```\n\n- [ ] t");
+        assert_eq!(result, vec![
+            LineBlock::Normal,
+            LineBlock::CodeFence(None),
+            LineBlock::CodeFence(None),
+            LineBlock::Code(None),
+            LineBlock::Code(None),
+        ]);
+
+        // Fences inside a block quote and a list item close where their container says
+        let result = classify("> ```sh\n> ls\n> ```\n- ```\n  x\n  ```");
+        let sh = Some("sh".to_string());
+        assert_eq!(result[..3], [LineBlock::CodeFence(sh.clone()), LineBlock::Code(sh.clone()), LineBlock::CodeFence(sh)]);
+        assert_eq!(result[3..], [LineBlock::CodeFence(None), LineBlock::Code(None), LineBlock::CodeFence(None)]);
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::OnceLock;
+
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{FontStyle, Style, Theme, ThemeSet};
+use syntect::parsing::{SyntaxDefinition, SyntaxReference, SyntaxSet};
+
+/// syntect's bundled grammars have no TOML, so we ship our own.
+static TOML_SYNTAX: &str = include_str!("../../assets/syntaxes/TOML.sublime-syntax");
+
+/// Light theme that matches the grey code block background.
+static THEME_NAME: &str = "InspiredGitHub";
+
+/// The colour and font style of a highlighted piece of a code line.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CodeToken {
+    /// CSS hex colour, e.g. `#a71d5d`.
+    pub color: String,
+    pub bold: bool,
+    pub italic: bool,
+}
+
+impl CodeToken {
+    fn from_style(style: Style) -> Self {
+        let fg = style.foreground;
+        Self {
+            color: format!("#{:02x}{:02x}{:02x}", fg.r, fg.g, fg.b),
+            bold: style.font_style.contains(FontStyle::BOLD),
+            italic: style.font_style.contains(FontStyle::ITALIC),
+        }
+    }
+}
+
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(|| {
+        let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+        match SyntaxDefinition::load_from_str(TOML_SYNTAX, true, None) {
+            Ok(toml) => builder.add(toml),
+            Err(err) => eprintln!("Failed to load TOML syntax: {}", err),
+        }
+        builder.build()
+    })
+}
+
+fn theme() -> &'static Theme {
+    static THEME: OnceLock<Theme> = OnceLock::new();
+    THEME.get_or_init(|| {
+        let mut themes = ThemeSet::load_defaults().themes;
+        themes.remove(THEME_NAME).unwrap_or_default()
+    })
+}
+
+/// Finds the grammar for a fence info string such as `rust`, `rs`, `toml` or `sh`.
+fn find_syntax(language: &str) -> Option<&'static SyntaxReference> {
+    let token = match language.to_ascii_lowercase().as_str() {
+        "shell" | "console" | "shell-session" => "sh".to_string(),
+        other => other.to_string(),
+    };
+    syntax_set().find_syntax_by_token(&token)
+}
+
+/// Highlights the lines of one fenced code block. Grammars keep state across
+/// lines (block comments, multi-line strings), so feed the lines in order.
+pub struct CodeHighlighter {
+    lines: HighlightLines<'static>,
+}
+
+impl CodeHighlighter {
+    /// Returns `None` when there is no grammar for `language`.
+    pub fn new(language: &str) -> Option<Self> {
+        let syntax = find_syntax(language)?;
+        Some(Self {
+            lines: HighlightLines::new(syntax, theme()),
+        })
+    }
+
+    /// Splits a line into runs of text sharing the same token style.
+    pub fn highlight(&mut self, line: &str) -> Vec<(CodeToken, String)> {
+        // The bundled grammars expect each line to end with its newline
+        let with_newline = format!("{}\n", line);
+        let ranges = match self.lines.highlight_line(&with_newline, syntax_set()) {
+            Ok(ranges) => ranges,
+            Err(err) => {
+                eprintln!("Failed to highlight line: {}", err);
+                return vec![(CodeToken::from_style(Style::default()), line.to_string())];
+            }
+        };
+
+        let mut tokens: Vec<(CodeToken, String)> = Vec::new();
+        for (style, text) in ranges {
+            let text = text.trim_end_matches('\n');
+            if text.is_empty() {
+                continue;
+            }
+            let token = CodeToken::from_style(style);
+            match tokens.last_mut() {
+                Some((last, last_text)) if *last == token => last_text.push_str(text),
+                _ => tokens.push((token, text.to_string())),
+            }
+        }
+        tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rust_line_is_split_into_tokens() {
+        let mut highlighter = CodeHighlighter::new("rust").unwrap();
+        let tokens = highlighter.highlight("let answer = 42;");
+
+        let text: String = tokens.iter().map(|(_, text)| text.as_str()).collect();
+        assert_eq!(text, "let answer = 42;");
+        assert!(tokens.len() > 1);
+        assert_ne!(tokens[0].0, tokens[tokens.len() - 2].0);
+    }
+
+    #[test]
+    fn test_bundled_toml_and_shell_aliases() {
+        let mut toml = CodeHighlighter::new("toml").unwrap();
+        assert!(toml.highlight("name = \"editor\"").len() > 1);
+
+        assert!(CodeHighlighter::new("shell").is_some());
+        assert!(CodeHighlighter::new("no-such-language").is_none());
+    }
+
+    #[test]
+    fn test_state_carries_across_lines() {
+        let mut highlighter = CodeHighlighter::new("rust").unwrap();
+        let comment = highlighter.highlight("/* start");
+        let inside = highlighter.highlight("still comment");
+
+        assert_eq!(inside.len(), 1);
+        assert_eq!(inside[0].0, comment[0].0);
+    }
+}
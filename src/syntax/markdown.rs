@@ -1,3 +1,4 @@
+use super::table::TableRowKind;
 use super::text::*;
 
 #[derive(Debug)]
@@ -14,11 +15,42 @@ pub struct MarkDownStyle {
     pub text: String,
     pub font_size: usize,
     pub bold: bool,
+    pub italic: bool,
+    pub strikethrough: bool,
+    pub code: bool,
     pub color: String,
-    pub width: String,
+    pub indent: usize,
+    /// Width in characters of a table column, so the cells of a table form a grid.
+    pub column_width: Option<usize>,
+    /// Width in pixels, or `None` to size the cell to its text.
+    pub width: Option<f64>,
     pub flex_grow: i32
 }
 
+/// The font size in px a cell is drawn at, by heading level or text type.
+pub fn font_size(syntax: &MarkDownElements) -> usize {
+    match syntax {
+        MarkDownElements::Heading(heading) => match heading {
+            HeadingLevel::H1(_) => 32, // H1: 32px
+            HeadingLevel::H2(_) => 28, // H2: 28px
+            HeadingLevel::H3(_) => 24, // H3: 24px
+            HeadingLevel::H4(_) => 20, // H4: 20px
+            HeadingLevel::H5(_) => 18, // H5: 18px
+            HeadingLevel::H6(_) => 16, // H6: 16px
+        },
+        MarkDownElements::ListItem(_) => 16,
+        MarkDownElements::CodeBlock(_) => 16,
+        MarkDownElements::TableCell(_) => 16,
+        MarkDownElements::PlainText(_) => 16, // Plain text: 16px
+        MarkDownElements::BoldText(_) => 16,  // Bold text: 16px
+        MarkDownElements::Italic(_) => 16,
+        MarkDownElements::BoldItalic(_) => 16,
+        MarkDownElements::Strikethrough(_) => 16,
+        MarkDownElements::InlineCode(_) => 16,
+        MarkDownElements::EmptySpace => 16
+    }
+}
+
 pub fn compute_markdown_style_props(props: CellInfo) -> MarkDownStyle {
     // Extract text from MarkDownElements
     let text = match props.syntax.clone() {
@@ -30,46 +62,69 @@ pub fn compute_markdown_style_props(props: CellInfo) -> MarkDownStyle {
             HeadingLevel::H5(text) => text,
             HeadingLevel::H6(text) => text,
         },
+        MarkDownElements::ListItem(item) => item.text,
+        MarkDownElements::CodeBlock(line) => line.text,
+        MarkDownElements::TableCell(cell) => cell.text,
         MarkDownElements::PlainText(text) => text,
         MarkDownElements::BoldText(text) => text,
+        MarkDownElements::Italic(text) => text,
+        MarkDownElements::BoldItalic(text) => text,
+        MarkDownElements::Strikethrough(text) => text,
+        MarkDownElements::InlineCode(text) => text,
         MarkDownElements::EmptySpace => "".to_string()
     };
 
-    let width = if let Some(w) = props.width {
-        format!("{}px", w)
-    }
-    else {
-        "auto".to_string()
-    };
-    // let width = props.width.map_or("auto".to_string(), |w| format!("{}px", w));
-
     // Set font size based on heading level or text type
-    let font_size = match props.syntax.clone() {
-        MarkDownElements::Heading(heading) => match heading {
-            HeadingLevel::H1(_) => 32, // H1: 32px
-            HeadingLevel::H2(_) => 28, // H2: 28px
-            HeadingLevel::H3(_) => 24, // H3: 24px
-            HeadingLevel::H4(_) => 20, // H4: 20px
-            HeadingLevel::H5(_) => 18, // H5: 18px
-            HeadingLevel::H6(_) => 16, // H6: 16px
-        },
-        MarkDownElements::PlainText(_) => 16, // Plain text: 16px
-        MarkDownElements::BoldText(_) => 16,  // Bold text: 16px
-        MarkDownElements::EmptySpace => 16
-    };
+    let font_size = font_size(&props.syntax);
 
     // Set bold: headings and bold text are bold
     let bold = matches!(
         props.syntax,
-        MarkDownElements::Heading(_) | MarkDownElements::BoldText(_)
+        MarkDownElements::Heading(_) | MarkDownElements::BoldText(_) | MarkDownElements::BoldItalic(_) | MarkDownElements::ListItem(_)
+    ) || matches!(&props.syntax, MarkDownElements::TableCell(cell) if cell.kind == TableRowKind::Header);
+
+    let italic = matches!(
+        props.syntax,
+        MarkDownElements::Italic(_) | MarkDownElements::BoldItalic(_)
     );
 
-    // Color is always black
-    let color = "black".to_string();
+    // Highlighted code cells take their font style from the syntax theme
+    let (bold, italic) = match &props.syntax {
+        MarkDownElements::CodeBlock(CodeLine { token: Some(token), .. }) => (token.bold, token.italic),
+        _ => (bold, italic),
+    };
+
+    let strikethrough = matches!(props.syntax, MarkDownElements::Strikethrough(_));
+
+    // Inline code and code block rows get a monospace font on a grey background
+    let code = matches!(props.syntax, MarkDownElements::InlineCode(_) | MarkDownElements::CodeBlock(_));
+
+    // List markers are tinted so the bullet stands apart from the item text
+    let color = match &props.syntax {
+        MarkDownElements::ListItem(item) => match item.kind {
+            ListKind::Task(true) => "#2da44e".to_string(),
+            _ => "#6a737d".to_string(),
+        },
+        MarkDownElements::CodeBlock(line) if line.fence => "#6a737d".to_string(),
+        MarkDownElements::CodeBlock(CodeLine { token: Some(token), .. }) => token.color.clone(),
+        MarkDownElements::TableCell(cell) if cell.kind == TableRowKind::Delimiter => "#6a737d".to_string(),
+        _ => "black".to_string(),
+    };
+
+    // Nested list markers are pushed right by their depth
+    let indent = match &props.syntax {
+        MarkDownElements::ListItem(item) => item.depth * 16,
+        _ => 0,
+    };
+
+    let column_width = match &props.syntax {
+        MarkDownElements::TableCell(cell) => Some(cell.width),
+        _ => None,
+    };
 
     // let mut flex_grow = 0;
     // Set width and flex-grow: last column uses flex-grow: 1
-    let flex_grow = if props.col == props.num_cols - 1 {
+    let flex_grow = if props.col == props.num_cols - 1 && column_width.is_none() {
         1
 
         // format!("flex-grow: 1; width: {};", props.width)
@@ -82,19 +137,54 @@ pub fn compute_markdown_style_props(props: CellInfo) -> MarkDownStyle {
         text,
         font_size,
         bold,
+        italic,
+        strikethrough,
+        code,
         color,
-        width,
+        indent,
+        column_width,
+        width: props.width,
         flex_grow
     }
 }
 
 pub fn compute_markdown_style_string(props: MarkDownStyle) -> String {
-    format!(
-        "font-size: {}px; color: {}; font-weight: {}; width: {}px; flex-grow: {}",
+    let mut style = format!(
+        "font-size: {}px; color: {}; font-weight: {}; font-style: {}; width: {}; flex-grow: {}",
         props.font_size,
         props.color,
         if props.bold { "bold" } else { "normal" },
-        props.width,
+        if props.italic { "italic" } else { "normal" },
+        props.width.map_or("auto".to_string(), |width| format!("{}px", width)),
         props.flex_grow
-    )
-}
\ No newline at end of file
+    );
+    if props.strikethrough {
+        style.push_str("; text-decoration: line-through");
+    }
+    if props.indent > 0 {
+        style.push_str(&format!("; padding-left: {}px", props.indent));
+    }
+    if let Some(column_width) = props.column_width {
+        style.push_str(&format!("; font-family: 'CqMono', monospace; min-width: {}ch; white-space: pre", column_width));
+    }
+    if props.code {
+        style.push_str("; font-family: 'CqMono', monospace; background-color: #f0f0f0");
+    }
+    style
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_style_string_units() {
+        let cell = |width: Option<f64>, syntax: MarkDownElements| CellInfo { row: 0, col: 0, num_cols: 2, width, syntax };
+
+        let style = compute_markdown_style_string(compute_markdown_style_props(cell(Some(42.5), MarkDownElements::BoldText("**b**".to_string()))));
+        assert_eq!(style, "font-size: 16px; color: black; font-weight: bold; font-style: normal; width: 42.5px; flex-grow: 0");
+
+        let style = compute_markdown_style_string(compute_markdown_style_props(cell(None, MarkDownElements::PlainText("a".to_string()))));
+        assert!(style.contains("; width: auto;"), "{}", style);
+    }
+}
@@ -1,2 +1,5 @@
 pub mod text;
+pub mod block;
+pub mod highlight;
+pub mod table;
 pub mod markdown;
\ No newline at end of file
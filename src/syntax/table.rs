@@ -0,0 +1,225 @@
+/// Which part of a GFM pipe table a row is.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TableRowKind {
+    Header,
+    /// The `| --- | :-: |` line under the header.
+    Delimiter,
+    Body,
+}
+
+impl TableRowKind {
+    /// The kind of the `row`-th line of a table.
+    pub fn of_row(row: usize) -> Self {
+        match row {
+            0 => TableRowKind::Header,
+            1 => TableRowKind::Delimiter,
+            _ => TableRowKind::Body,
+        }
+    }
+}
+
+/// Column alignment, as written in the delimiter row.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Alignment {
+    None,
+    Left,
+    Center,
+    Right,
+}
+
+impl Alignment {
+    fn parse(delimiter: &str) -> Self {
+        match (delimiter.starts_with(':'), delimiter.ends_with(':') && delimiter.len() > 1) {
+            (true, true) => Alignment::Center,
+            (true, false) => Alignment::Left,
+            (false, true) => Alignment::Right,
+            (false, false) => Alignment::None,
+        }
+    }
+}
+
+/// One column of a table row.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TableCell {
+    pub kind: TableRowKind,
+    pub column: usize,
+    /// Width in characters of the widest cell in this column, so every row lines up.
+    pub width: usize,
+    /// Raw source text, including the leading pipe (and the closing pipe on the last column).
+    pub text: String,
+}
+
+/// Byte offsets of the pipes that separate columns; `\|` is an escaped pipe.
+fn pipe_positions(line: &str) -> Vec<usize> {
+    let mut pipes = Vec::new();
+    let mut escaped = false;
+    for (idx, c) in line.char_indices() {
+        if c == '|' && !escaped {
+            pipes.push(idx);
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    pipes
+}
+
+/// Splits a table row into the raw text of its columns, without losing any
+/// characters: `"| a | bb |"` becomes `["| a ", "| bb |"]`.
+pub fn split_row(line: &str) -> Vec<String> {
+    let mut pipes = pipe_positions(line);
+
+    // A closing pipe belongs to the last column rather than opening a new one
+    let closing = pipes.last().copied().filter(|&idx| line[idx + 1..].trim().is_empty() && pipes.len() > 1);
+    if closing.is_some() {
+        pipes.pop();
+    }
+
+    let mut starts = pipes;
+    // Text before the first pipe is its own column unless it is only indentation
+    if starts.first().is_none_or(|&first| !line[..first].trim().is_empty()) {
+        starts.insert(0, 0);
+    } else {
+        starts[0] = 0;
+    }
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(idx, &start)| {
+            let end = starts.get(idx + 1).copied().unwrap_or(line.len());
+            line[start..end].to_string()
+        })
+        .collect()
+}
+
+/// The text of a column without its pipes and surrounding spaces.
+pub fn cell_content(raw: &str) -> &str {
+    let mut content = raw.trim();
+    content = content.strip_prefix('|').unwrap_or(content);
+    if content.ends_with('|') && !content.ends_with("\\|") {
+        content = &content[..content.len() - 1];
+    }
+    content.trim()
+}
+
+/// Where the caret goes when Tab moves into a column: just after `"| "`.
+pub fn content_start(raw: &str) -> usize {
+    let after_pipe = raw.find('|').map(|idx| idx + 1).unwrap_or(0);
+    let spaces = raw[after_pipe..].len() - raw[after_pipe..].trim_start().len();
+    after_pipe + spaces
+}
+
+/// The cell Tab (or Shift+Tab when `backwards`) moves to from (`row`, `col`),
+/// given the number of columns in each table row. The delimiter row is skipped.
+pub fn next_cell(columns: &[usize], row: usize, col: usize, backwards: bool) -> Option<(usize, usize)> {
+    let editable = |r: usize| TableRowKind::of_row(r) != TableRowKind::Delimiter && columns[r] > 0;
+    if backwards {
+        if col > 0 {
+            return Some((row, col - 1));
+        }
+        (0..row).rev().find(|&r| editable(r)).map(|r| (r, columns[r] - 1))
+    } else {
+        if col + 1 < columns.get(row).copied().unwrap_or(0) {
+            return Some((row, col + 1));
+        }
+        (row + 1..columns.len()).find(|&r| editable(r)).map(|r| (r, 0))
+    }
+}
+
+/// Re-pads the lines of one table so every column has the same width,
+/// rewriting the delimiter row to match and keeping its alignment colons.
+pub fn format_table(lines: &[&str]) -> Vec<String> {
+    let rows: Vec<Vec<String>> = lines
+        .iter()
+        .map(|line| split_row(line).iter().map(|cell| cell_content(cell).to_string()).collect())
+        .collect();
+    let num_cols = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+
+    let alignments: Vec<Alignment> = (0..num_cols)
+        .map(|col| {
+            rows.get(1)
+                .and_then(|row| row.get(col))
+                .map(|delimiter| Alignment::parse(delimiter))
+                .unwrap_or(Alignment::None)
+        })
+        .collect();
+
+    let widths: Vec<usize> = (0..num_cols)
+        .map(|col| {
+            rows.iter()
+                .enumerate()
+                .filter(|(idx, _)| TableRowKind::of_row(*idx) != TableRowKind::Delimiter)
+                .filter_map(|(_, row)| row.get(col))
+                .map(|content| content.chars().count())
+                .max()
+                .unwrap_or(0)
+                .max(3)
+        })
+        .collect();
+
+    rows.iter()
+        .enumerate()
+        .map(|(idx, row)| {
+            let cells: Vec<String> = (0..num_cols)
+                .map(|col| {
+                    let width = widths[col];
+                    if TableRowKind::of_row(idx) == TableRowKind::Delimiter {
+                        return match alignments[col] {
+                            Alignment::None => "-".repeat(width),
+                            Alignment::Left => format!(":{}", "-".repeat(width - 1)),
+                            Alignment::Right => format!("{}:", "-".repeat(width - 1)),
+                            Alignment::Center => format!(":{}:", "-".repeat(width - 2)),
+                        };
+                    }
+                    let content = row.get(col).map(|s| s.as_str()).unwrap_or("");
+                    match alignments[col] {
+                        Alignment::Right => format!("{:>width$}", content),
+                        Alignment::Center => format!("{:^width$}", content),
+                        _ => format!("{:<width$}", content),
+                    }
+                })
+                .collect();
+            format!("| {} |", cells.join(" | "))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_row_is_lossless() {
+        assert_eq!(split_row("| a | bb |"), vec!["| a ", "| bb |"]);
+        assert_eq!(split_row("a | b"), vec!["a ", "| b"]);
+        assert_eq!(split_row("| a \\| b |"), vec!["| a \\| b |"]);
+        assert_eq!(split_row("|---|:-:|"), vec!["|---", "|:-:|"]);
+
+        assert_eq!(cell_content("| bb |"), "bb");
+        assert_eq!(cell_content("| a \\|"), "a \\|");
+        assert_eq!(content_start("| bb |"), 2);
+    }
+
+    #[test]
+    fn test_format_table_pads_columns() {
+        let lines = ["|Name|Qty|", "|:--|--:|", "|apple|3|", "|kiwi|"];
+        let expected = vec![
+            "| Name  | Qty |",
+            "| :---- | --: |",
+            "| apple |   3 |",
+            "| kiwi  |     |",
+        ];
+
+        assert_eq!(format_table(&lines), expected);
+    }
+
+    #[test]
+    fn test_next_cell_skips_delimiter_row() {
+        let columns = [2, 2, 2];
+
+        assert_eq!(next_cell(&columns, 0, 0, false), Some((0, 1)));
+        assert_eq!(next_cell(&columns, 0, 1, false), Some((2, 0)));
+        assert_eq!(next_cell(&columns, 2, 1, false), None);
+        assert_eq!(next_cell(&columns, 2, 0, true), Some((0, 1)));
+        assert_eq!(next_cell(&columns, 0, 0, true), None);
+    }
+}
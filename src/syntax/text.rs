@@ -1,3 +1,11 @@
+use std::ops::Range;
+
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+
+use super::block::{CodeBlocks, LineBlock};
+use super::highlight::{CodeHighlighter, CodeToken};
+use super::table::{self, TableCell, TableRowKind};
+
 static NEWLINE_PLACEHOLDER: &str = "__LITERAL_NEWLINE__";
 
 #[derive(Debug, PartialEq, Clone)]
@@ -10,14 +18,386 @@ pub enum HeadingLevel {
     H6(String),
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ListKind {
+    Bullet,
+    Ordered(u64),
+    /// A `- [ ]` / `- [x]` item; `true` when checked.
+    Task(bool),
+}
+
+/// The marker cell at the start of a list item line, e.g. `  - [ ] `.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ListItem {
+    pub kind: ListKind,
+    /// How many lists this item is nested in, 0 for a top-level item.
+    pub depth: usize,
+    /// Raw marker text, including indentation and the spaces after it.
+    pub text: String,
+}
+
+/// A row of a fenced or indented code block, or one highlighted piece of it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CodeLine {
+    /// The info string language of the block, e.g. `rust`.
+    pub language: Option<String>,
+    /// `true` for the opening and closing fence rows.
+    pub fence: bool,
+    /// Syntax highlighting colour, when the language has a grammar.
+    pub token: Option<CodeToken>,
+    pub text: String,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum MarkDownElements {
     Heading(HeadingLevel),
+    ListItem(ListItem),
+    CodeBlock(CodeLine),
+    TableCell(TableCell),
     PlainText(String),
     BoldText(String),
+    Italic(String),
+    BoldItalic(String),
+    Strikethrough(String),
+    InlineCode(String),
     EmptySpace
 }
 
+/// An element reported by pulldown-cmark that affects cell styling.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum SpanKind {
+    Heading(usize),
+    ListMarker(ListKind, usize),
+    Bold,
+    Italic,
+    Strikethrough,
+    Code,
+    /// A whole GFM table; its lines are split into columns instead of spans.
+    Table,
+    /// A list, block quote, code block or HTML block. It is not styled, but
+    /// the blank rows inside it are not breaks, see `ParsedDocument`.
+    Block,
+}
+
+impl SpanKind {
+    fn is_emphasis(&self) -> bool {
+        matches!(self, SpanKind::Bold | SpanKind::Italic | SpanKind::Strikethrough)
+    }
+}
+
+/// The combined formatting of a piece of a line, from every span covering it.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+struct CellStyle {
+    list: Option<(ListKind, usize)>,
+    heading: Option<usize>,
+    bold: bool,
+    italic: bool,
+    strikethrough: bool,
+    code: bool,
+}
+
+impl CellStyle {
+    fn with(mut self, kind: SpanKind) -> Self {
+        match kind {
+            SpanKind::Heading(level) => self.heading = Some(level),
+            SpanKind::ListMarker(kind, depth) => self.list = Some((kind, depth)),
+            SpanKind::Bold => self.bold = true,
+            SpanKind::Italic => self.italic = true,
+            SpanKind::Strikethrough => self.strikethrough = true,
+            SpanKind::Code => self.code = true,
+            SpanKind::Table | SpanKind::Block => {}
+        }
+        self
+    }
+
+    /// Headings win over inline formatting, since a heading row is styled as a whole.
+    /// Inside a line, code beats strikethrough, which beats emphasis.
+    fn into_element(self, text: String) -> MarkDownElements {
+        if let Some((kind, depth)) = self.list {
+            MarkDownElements::ListItem(ListItem { kind, depth, text })
+        } else if let Some(level) = self.heading {
+            let heading = match level {
+                1 => HeadingLevel::H1(text),
+                2 => HeadingLevel::H2(text),
+                3 => HeadingLevel::H3(text),
+                4 => HeadingLevel::H4(text),
+                5 => HeadingLevel::H5(text),
+                _ => HeadingLevel::H6(text),
+            };
+            MarkDownElements::Heading(heading)
+        } else if self.code {
+            MarkDownElements::InlineCode(text)
+        } else if self.strikethrough {
+            MarkDownElements::Strikethrough(text)
+        } else if self.bold && self.italic {
+            MarkDownElements::BoldItalic(text)
+        } else if self.bold {
+            MarkDownElements::BoldText(text)
+        } else if self.italic {
+            MarkDownElements::Italic(text)
+        } else {
+            MarkDownElements::PlainText(text)
+        }
+    }
+}
+
+/// A list item marker parsed from the start of a source line.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ListMarker {
+    pub kind: ListKind,
+    /// Leading spaces before the bullet or number.
+    pub indent: usize,
+    /// Byte length of the whole marker: indentation, bullet, task box and trailing spaces.
+    pub len: usize,
+    /// The bullet character (`-`, `*`, `+`) or ordered delimiter (`.`, `)`).
+    pub delimiter: char,
+    /// Width of the bullet or number, delimiter and following spaces, without the
+    /// indentation or task box. A child item is indented by this much.
+    pub width: usize,
+}
+
+impl ListMarker {
+    /// Recognises `- `, `* `, `+ `, `1. `, `1) ` and task boxes such as `- [x] `.
+    pub fn parse(line: &str) -> Option<Self> {
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        let rest = &line[indent..];
+
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        let (mut kind, delimiter, mut len) = if digits > 0 && digits <= 9 {
+            let delimiter = rest[digits..].chars().next()?;
+            if delimiter != '.' && delimiter != ')' {
+                return None;
+            }
+            (ListKind::Ordered(rest[..digits].parse().ok()?), delimiter, digits + 1)
+        } else {
+            let bullet = rest.chars().next()?;
+            if !matches!(bullet, '-' | '*' | '+') {
+                return None;
+            }
+            (ListKind::Bullet, bullet, 1)
+        };
+
+        // The marker must be followed by a space or end the line
+        let spaces = rest[len..].len() - rest[len..].trim_start_matches(' ').len();
+        if spaces == 0 && len < rest.len() {
+            return None;
+        }
+        // More than four spaces start indented code inside the item, so only one counts
+        let width = if spaces == 0 || spaces > 4 { len + 1 } else { len + spaces };
+        len += spaces;
+
+        if kind == ListKind::Bullet {
+            let task = &rest[len..];
+            let checked = if task.starts_with("[ ]") {
+                Some(false)
+            } else if task.starts_with("[x]") || task.starts_with("[X]") {
+                Some(true)
+            } else {
+                None
+            };
+            if let Some(checked) = checked {
+                let after = &task[3..];
+                let task_spaces = after.len() - after.trim_start_matches(' ').len();
+                if task_spaces > 0 || after.is_empty() {
+                    kind = ListKind::Task(checked);
+                    len += 3 + task_spaces;
+                }
+            }
+        }
+
+        Some(Self { kind, indent, len: indent + len, delimiter, width })
+    }
+
+    /// The marker that continues the list on the next line: same bullet and
+    /// indentation, the following number, an unchecked task box.
+    pub fn next(&self) -> String {
+        let indent = " ".repeat(self.indent);
+        match self.kind {
+            ListKind::Bullet => format!("{}{} ", indent, self.delimiter),
+            ListKind::Ordered(number) => format!("{}{}{} ", indent, number + 1, self.delimiter),
+            ListKind::Task(_) => format!("{}{} [ ] ", indent, self.delimiter),
+        }
+    }
+}
+
+/// Spaces Tab adds in front of a line: the marker width for a list item, so it
+/// nests one level deeper, otherwise four (tabs are expanded to four spaces).
+pub fn indent_width(line: &str) -> usize {
+    ListMarker::parse(line).map(|marker| marker.width).unwrap_or(4)
+}
+
+/// Removes up to one level of indentation; returns the new line and how many
+/// bytes were removed. A leading tab is a level on its own.
+pub fn outdent_line(line: &str) -> (String, usize) {
+    if let Some(rest) = line.strip_prefix('\t') {
+        return (rest.to_string(), 1);
+    }
+    let leading = line.len() - line.trim_start_matches(' ').len();
+    let removed = leading.min(indent_width(line));
+    (line[removed..].to_string(), removed)
+}
+
+/// The rows of a document whose parse changed after an edit: `removed` rows
+/// of the old parse, starting at `start`, are replaced by `rows`.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct RowPatch {
+    pub start: usize,
+    pub removed: usize,
+    pub rows: Vec<Vec<MarkDownElements>>,
+}
+
+impl RowPatch {
+    pub fn is_empty(&self) -> bool {
+        self.removed == 0 && self.rows.is_empty()
+    }
+
+    /// The rows of the new parse that the patch covers.
+    pub fn new_rows(&self) -> Range<usize> {
+        self.start..self.start + self.rows.len()
+    }
+
+    /// Where `rows` of the old parse are in the new one, grown to cover the
+    /// patched rows too, so the rows changed by edits applied one after the
+    /// other add up. With no `rows`, just the patched ones.
+    pub fn follow(&self, rows: Option<Range<usize>>) -> Range<usize> {
+        let new_rows = self.new_rows();
+        let Some(rows) = rows else {
+            return new_rows;
+        };
+        let end = self.start + self.removed;
+        let moved = |row: usize| if row >= end { row - self.removed + self.rows.len() } else { row.min(self.start) };
+        moved(rows.start).min(new_rows.start)..moved(rows.end).max(new_rows.end)
+    }
+
+    pub fn apply(&self, parsed: &mut Vec<Vec<MarkDownElements>>) {
+        parsed.splice(self.start..self.start + self.removed, self.rows.iter().cloned());
+    }
+}
+
+/// The lines of a document, one per row. They are read one at a time, so
+/// a reparse only reads the rows around an edit.
+pub trait SourceLines {
+    fn line_count(&self) -> usize;
+    fn source_line(&self, index_i: usize) -> String;
+}
+
+impl SourceLines for [String] {
+    fn line_count(&self) -> usize {
+        self.len()
+    }
+
+    fn source_line(&self, index_i: usize) -> String {
+        self[index_i].clone()
+    }
+}
+
+/// A document's parse, kept up to date by reparsing only the rows around an
+/// edit. Along with the rows it keeps the breaks: blank rows outside every
+/// list, block quote, code block and HTML block, where the document can be
+/// cut without changing how the rows on either side parse.
+#[derive(Debug, Clone, Default)]
+pub struct ParsedDocument {
+    rows: Vec<Vec<MarkDownElements>>,
+    breaks: Vec<bool>,
+}
+
+impl ParsedDocument {
+    pub fn rows(&self) -> &[Vec<MarkDownElements>] {
+        &self.rows
+    }
+
+    /// Brings the parse up to date with `lines` after an edit and returns the
+    /// rows whose parse changed. `dirty` is the range of `lines` the edit
+    /// inserted, removed or changed; the rows outside it must be unchanged,
+    /// only shifted by the rows the edit added or removed.
+    ///
+    /// The range is widened to cuts that `starts_block` accepts, so a code
+    /// fence, list or HTML block that the edit opened, closed or joined is
+    /// parsed as a whole. The cut before the edit is taken from the old breaks.
+    /// The cut after it must be a break in the new parse as well, or the rest
+    /// of the document is parsed too.
+    pub fn update(&mut self, text_processor: &TextProcessor, lines: &(impl SourceLines + ?Sized), dirty: Range<usize>) -> RowPatch {
+        let len = lines.line_count();
+        let old_len = self.rows.len();
+        // Old row index = new row index - shift, for rows after the edit
+        let shift = len as isize - old_len as isize;
+        let old_row = |row: usize| row.checked_add_signed(-shift);
+
+        // The rows before the edit are the same in both documents
+        let mut start = dirty.start.min(old_len).min(len);
+        while start > 0 && !(start < len && starts_block(&lines.source_line(start), self.breaks[start - 1])) {
+            start -= 1;
+        }
+
+        // The first cut after the edit that the old parse allows
+        let boundary = ((start + 1).max(dirty.end + 1)..len)
+            .find(|&row| {
+                let break_before = old_row(row - 1).and_then(|old| self.breaks.get(old)) == Some(&true);
+                starts_block(&lines.source_line(row), break_before)
+            })
+            .unwrap_or(len);
+        let parse = |end: usize| if start == end {
+            (Vec::new(), Vec::new())
+        } else {
+            let source: Vec<String> = (start..end).map(|row| lines.source_line(row)).collect();
+            text_processor.parse(&source.join("\n"))
+        };
+        let mut end = boundary;
+        let (mut rows, mut breaks) = parse(end);
+        // A block the edit left open runs over the blank row; parse on to the end
+        if end < len && breaks.last() != Some(&true) {
+            end = len;
+            (rows, breaks) = parse(end);
+        }
+        let old_end = if end == len {
+            old_len
+        } else {
+            old_row(end).unwrap_or(old_len)
+        };
+        let old_start = start.min(old_end);
+        self.breaks.splice(old_start..old_end, breaks);
+
+        // Leave out the rows that parse the same as before
+        let old_rows = &self.rows[old_start..old_end];
+        let same_start = rows.iter().zip(old_rows).take_while(|(a, b)| a == b).count();
+        let same_end = rows[same_start..].iter().rev()
+            .zip(old_rows[same_start..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let patch = RowPatch {
+            start: start + same_start,
+            removed: old_rows.len() - same_start - same_end,
+            rows: rows[same_start..rows.len() - same_end].to_vec(),
+        };
+        patch.apply(&mut self.rows);
+        patch
+    }
+}
+
+/// Byte offset where each line of `source` starts.
+pub(super) fn line_starts(source: &str) -> Vec<usize> {
+    let mut line_starts = vec![0];
+    line_starts.extend(source.match_indices('\n').map(|(idx, _)| idx + 1));
+    line_starts
+}
+
+/// The line that byte `offset` is on.
+pub(super) fn line_of(line_starts: &[usize], offset: usize) -> usize {
+    line_starts.partition_point(|&start| start <= offset) - 1
+}
+
+/// Whether a document may be cut before `line` for parsing: the row before
+/// it is a break, and the line itself starts a new top-level block, so
+/// parsing from `line` on gives the same rows as parsing the whole document.
+fn starts_block(line: &str, break_before: bool) -> bool {
+    break_before && !is_blank(line) && !line.starts_with([' ', '\t'])
+}
+
+/// A line with nothing but whitespace, which ends paragraphs and tables.
+fn is_blank(line: &str) -> bool {
+    line.chars().all(|c| matches!(c, ' ' | '\t' | '\r'))
+}
+
 pub struct TextProcessor {
     language: String,
 }
@@ -29,125 +409,283 @@ impl TextProcessor {
         }
     }
 
+    /// The pulldown-cmark extensions the editor understands on top of CommonMark.
+    pub(super) fn parser_options() -> Options {
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS
+    }
+
     /// Processes markdown text into a Vec<Vec<MarkDownElements>> where:
-    /// - Outer Vec represents rows (newlines or new blocks like headers)
+    /// - Outer Vec represents rows, one per source line
     /// - Inner Vec contains MarkDownElements enums (Heading, PlainText, BoldText)
     /// - Text content retains original markdown formatting (e.g., #, **, __)
-    pub fn process_markdown(&self, input: String) -> Vec<Vec<MarkDownElements>> {
-        // Standardize input
-        let temp = input.replace("\\n", NEWLINE_PLACEHOLDER);
-        let without_tabs = temp.replace("\t", "    ");
-        let standardized_newlines = without_tabs.replace("\r\n", "\n").replace("\r", "\n");
-    
-        // Split into lines
-        let lines: Vec<&str> = standardized_newlines.lines().collect();
-        let mut result: Vec<Vec<MarkDownElements>> = Vec::new();
-        let mut empty_count = 0;
-    
-        for line in lines {
-            // Handle empty lines
-            if line.trim().is_empty() {
-                empty_count += 1;
+    ///
+    /// Everything comes from pulldown-cmark's offset iterator. Code block rows
+    /// (`block::CodeBlocks`) skip inline parsing and become `CodeBlock` cells,
+    /// split by syntax highlighting token when the fence names a known language.
+    /// Every other span is mapped back onto the source lines it covers, and each
+    /// line is cut into cells wherever the formatting changes.
+    ///
+    /// Nothing is normalized: joining the cells of each row and the rows with
+    /// `'\n'` (`markdown_to_string`) gives back `source` byte for byte, tabs, `'\r'`
+    /// and trailing spaces included.
+    pub fn process_markdown(&self, source: String) -> Vec<Vec<MarkDownElements>> {
+        self.parse(&source).0
+    }
+
+    /// `process_markdown`, along with the breaks of `ParsedDocument`: whether
+    /// each row is blank and outside every list, block quote, code block and
+    /// HTML block. A block that runs to the end of `source` takes in the blank
+    /// rows after it, since whatever is typed there joins it.
+    fn parse(&self, source: &str) -> (Vec<Vec<MarkDownElements>>, Vec<bool>) {
+        let line_starts = line_starts(source);
+        let (spans, code_blocks) = Self::collect_spans(source);
+
+        // Bucket every span by the lines it touches
+        let mut line_spans: Vec<Vec<(Range<usize>, SpanKind)>> = vec![Vec::new(); line_starts.len()];
+        let mut tables: Vec<Range<usize>> = Vec::new();
+        let mut blocks: Vec<Range<usize>> = Vec::new();
+        for (range, kind) in spans {
+            if range.is_empty() {
                 continue;
             }
-    
-            while empty_count > 0 {
-                result.push(vec![MarkDownElements::EmptySpace]);
-                empty_count -= 1;
+            let first_line = line_of(&line_starts, range.start);
+            let last_line = line_of(&line_starts, range.end - 1);
+            if kind == SpanKind::Table {
+                tables.push(first_line..last_line + 1);
+                continue;
             }
-    
-            let mut row: Vec<MarkDownElements> = Vec::new();
-    
-            // Check for headers (#, ##, ###, etc.)
-            let trimmed = line.trim_start();
-            let header_level = line.chars().take_while(|c| *c == '#').count();
-            if header_level > 0 && header_level <= 6 && trimmed.starts_with('#') && trimmed.len() > header_level {
-                if trimmed.chars().nth(header_level) == Some(' ') {
-                    let header_text = format!("#{} {}", &"#".repeat(header_level - 1), trimmed[header_level + 1..].to_string());
-                    let heading = match header_level {
-                        1 => HeadingLevel::H1(header_text),
-                        2 => HeadingLevel::H2(header_text),
-                        3 => HeadingLevel::H3(header_text),
-                        4 => HeadingLevel::H4(header_text),
-                        5 => HeadingLevel::H5(header_text),
-                        6 => HeadingLevel::H6(header_text),
-                        _ => unreachable!(),
-                    };
-                    row.push(MarkDownElements::Heading(heading));
-                    result.push(row);
-                    continue;
+            if kind == SpanKind::Block {
+                blocks.push(range);
+                continue;
+            }
+            for spans in &mut line_spans[first_line..=last_line] {
+                spans.push((range.clone(), kind));
+            }
+        }
+
+        let lines: Vec<&str> = source.split('\n').collect();
+        let table_cells = Self::split_tables(&lines, &tables);
+
+        let mut breaks: Vec<bool> = lines.iter().map(|line| is_blank(line)).collect();
+        for block in blocks {
+            let first = line_of(&line_starts, block.start);
+            let mut last = line_of(&line_starts, block.end - 1);
+            if block.end == source.len() {
+                last = lines.len() - 1;
+            } else {
+                // Lists take in the blank lines after them
+                while last > first && is_blank(lines[last]) {
+                    last -= 1;
                 }
             }
-    
-            // Process inline elements (bold and plain text)
-            let mut current = line;
-            while !current.is_empty() {
-                // Check for bold (**text** or __text__)
-                let bold_patterns = vec![("**", "**"), ("__", "__")];
-                let mut found_bold = false;
-    
-                for (open, close) in bold_patterns.clone() {
-                    if current.starts_with(open) {
-                        // Find closing delimiter
-                        if let Some(end_idx) = current[open.len()..].find(close) {
-                            let bold_text = &current[..open.len() + end_idx + close.len()];
-                            if bold_text.len() > open.len() + close.len() {
-                                row.push(MarkDownElements::BoldText(bold_text.to_string()));
-                                // Move past the bold text
-                                let bold_end = open.len() + end_idx + close.len();
-                                // Check for trailing spaces after bold text
-                                let trailing = &current[bold_end..];
-                                let trailing_spaces = trailing.chars().take_while(|c| c.is_whitespace()).count();
-                                if trailing_spaces > 0 {
-                                    row.push(MarkDownElements::PlainText(" ".repeat(trailing_spaces)));
-                                    current = &current[bold_end + trailing_spaces..];
-                                } else {
-                                    current = &current[bold_end..];
-                                }
-                                found_bold = true;
-                                break;
-                            }
-                        }
-                    }
+            breaks[first..=last].fill(false);
+        }
+
+        let line_blocks = code_blocks.classify(&line_starts, source.len());
+        let mut highlighter: Option<CodeHighlighter> = None;
+        let rows = lines
+            .iter()
+            .zip(line_starts.iter())
+            .zip(line_spans.iter())
+            .zip(table_cells)
+            .enumerate()
+            .map(|(row, (((&line, &line_start), spans), table_row))| match line_blocks[row].clone() {
+                LineBlock::Normal => table_row.unwrap_or_else(|| Self::split_line(line, line_start, spans)),
+                LineBlock::CodeFence(language) => {
+                    // A fence either opens a block, which gets a fresh highlighter, or closes it
+                    let opens = line_blocks.get(row + 1) == Some(&LineBlock::Code(language.clone()));
+                    highlighter = language.as_deref()
+                        .filter(|_| opens)
+                        .and_then(CodeHighlighter::new);
+                    vec![MarkDownElements::CodeBlock(CodeLine { language, fence: true, token: None, text: line.to_string() })]
                 }
-    
-                if !found_bold {
-                    // Extract plain text until next bold marker or end
-                    let next_bold = bold_patterns
-                        .iter()
-                        .filter_map(|(open, _)| current.find(open).map(|idx| (idx, open)))
-                        .min_by_key(|&(idx, _)| idx);
-    
-                    match next_bold {
-                        Some((idx, _)) if idx > 0 => {
-                            let plain_text = current[..idx].to_string();
-                            if !plain_text.trim().is_empty() {
-                                row.push(MarkDownElements::PlainText(plain_text));
-                            }
-                            current = &current[idx..];
-                        }
-                        _ => {
-                            let plain_text = current.to_string();
-                            if !plain_text.trim().is_empty() {
-                                row.push(MarkDownElements::PlainText(plain_text));
-                            }
-                            current = "";
-                        }
+                LineBlock::Code(language) => Self::split_code_line(line, language, highlighter.as_mut()),
+            })
+            .collect();
+        (rows, breaks)
+    }
+
+    /// Splits the lines of every table into one `TableCell` per column, sized to
+    /// the widest cell of that column. Lines outside a table get `None`.
+    fn split_tables(lines: &[&str], tables: &[Range<usize>]) -> Vec<Option<Vec<MarkDownElements>>> {
+        let mut rows: Vec<Option<Vec<MarkDownElements>>> = vec![None; lines.len()];
+        for table in tables {
+            let raw_rows: Vec<Vec<String>> = lines[table.clone()].iter().map(|line| table::split_row(line)).collect();
+            let num_cols = raw_rows.iter().map(|row| row.len()).max().unwrap_or(0);
+            let widths: Vec<usize> = (0..num_cols)
+                .map(|col| raw_rows.iter().filter_map(|row| row.get(col)).map(|text| text.chars().count()).max().unwrap_or(0))
+                .collect();
+
+            for (row_idx, raw_row) in raw_rows.into_iter().enumerate() {
+                let cells = raw_row
+                    .into_iter()
+                    .enumerate()
+                    .map(|(column, text)| MarkDownElements::TableCell(TableCell {
+                        kind: TableRowKind::of_row(row_idx),
+                        column,
+                        width: widths[column],
+                        text,
+                    }))
+                    .collect();
+                rows[table.start + row_idx] = Some(cells);
+            }
+        }
+        rows
+    }
+
+    /// Line ranges (end exclusive) of the GFM tables in `source`.
+    pub fn table_line_ranges(&self, source: &str) -> Vec<Range<usize>> {
+        let line_starts = line_starts(source);
+
+        Self::collect_spans(source).0
+            .into_iter()
+            .filter(|(range, kind)| *kind == SpanKind::Table && !range.is_empty())
+            .map(|(range, _)| line_of(&line_starts, range.start)..line_of(&line_starts, range.end - 1) + 1)
+            .collect()
+    }
+
+    /// Re-pads every table in `source` so its columns line up in the raw text.
+    pub fn align_tables(&self, source: &str) -> String {
+        self.align_tables_where(source, |_| true)
+    }
+
+    /// Like `align_tables`, but only for the tables whose line range `align`
+    /// accepts; the others are left as they are.
+    pub fn align_tables_where(&self, source: &str, align: impl Fn(&Range<usize>) -> bool) -> String {
+        let mut lines: Vec<String> = source.split('\n').map(|line| line.to_string()).collect();
+        for table in self.table_line_ranges(source).into_iter().filter(|table| align(table)) {
+            let table_lines: Vec<&str> = lines[table.clone()].iter().map(|line| line.as_str()).collect();
+            let aligned = table::format_table(&table_lines);
+            lines.splice(table, aligned);
+        }
+        lines.join("\n")
+    }
+
+    /// Turns a line of code into one `CodeBlock` cell per highlighting token.
+    fn split_code_line(line: &str, language: Option<String>, highlighter: Option<&mut CodeHighlighter>) -> Vec<MarkDownElements> {
+        let tokens = highlighter.map(|highlighter| highlighter.highlight(line)).unwrap_or_default();
+        if tokens.is_empty() {
+            return vec![MarkDownElements::CodeBlock(CodeLine { language, fence: false, token: None, text: line.to_string() })];
+        }
+        tokens
+            .into_iter()
+            .map(|(token, text)| MarkDownElements::CodeBlock(CodeLine {
+                language: language.clone(),
+                fence: false,
+                token: Some(token),
+                text,
+            }))
+            .collect()
+    }
+
+    /// Runs pulldown-cmark over the source and keeps the byte ranges of the
+    /// elements that change how a cell is rendered or hold blank rows, and the
+    /// code blocks.
+    fn collect_spans(source: &str) -> (Vec<(Range<usize>, SpanKind)>, CodeBlocks) {
+        let mut spans: Vec<(Range<usize>, SpanKind)> = Vec::new();
+        let mut code_blocks = CodeBlocks::default();
+        let mut list_depth = 0usize;
+        for (event, range) in Parser::new_ext(source, Self::parser_options()).into_offset_iter() {
+            code_blocks.feed(&event, &range);
+            let kind = match event {
+                Event::Start(Tag::List(_)) => {
+                    list_depth += 1;
+                    spans.push((range, SpanKind::Block));
+                    continue;
+                }
+                Event::End(TagEnd::List(_)) => {
+                    list_depth = list_depth.saturating_sub(1);
+                    continue;
+                }
+                Event::Start(Tag::Item) => {
+                    if let Some(marker_span) = Self::list_marker_span(source, range.start, list_depth.saturating_sub(1)) {
+                        spans.push(marker_span);
                     }
+                    continue;
+                }
+                Event::Start(Tag::Heading { level, .. }) => SpanKind::Heading(level as usize),
+                Event::Start(Tag::Strong) => SpanKind::Bold,
+                Event::Start(Tag::Emphasis) => SpanKind::Italic,
+                Event::Start(Tag::Strikethrough) => SpanKind::Strikethrough,
+                Event::Code(_) => SpanKind::Code,
+                Event::Start(Tag::Table(_)) => SpanKind::Table,
+                Event::Start(Tag::BlockQuote(_) | Tag::CodeBlock(_) | Tag::HtmlBlock) => SpanKind::Block,
+                _ => continue,
+            };
+
+            // Nested emphasis like `***x***` only has markers between the outer and
+            // inner span; give both the same range so the group stays one cell.
+            let range = match spans.last() {
+                Some((outer, outer_kind))
+                    if kind.is_emphasis()
+                        && outer_kind.is_emphasis()
+                        && outer.start <= range.start
+                        && range.end <= outer.end
+                        && Self::only_markers(&source[outer.start..range.start])
+                        && Self::only_markers(&source[range.end..outer.end]) =>
+                {
+                    outer.clone()
+                }
+                _ => range,
+            };
+            spans.push((range, kind));
+        }
+        (spans, code_blocks)
+    }
+
+    /// The span of a list item's marker, widened to the indentation before it
+    /// when the item is the first thing on its line.
+    fn list_marker_span(source: &str, item_start: usize, depth: usize) -> Option<(Range<usize>, SpanKind)> {
+        let line_start = source[..item_start].rfind('\n').map_or(0, |idx| idx + 1);
+        let line_end = source[item_start..].find('\n').map_or(source.len(), |idx| item_start + idx);
+        let start = if source[line_start..item_start].chars().all(|c| c == ' ') {
+            line_start
+        } else {
+            item_start
+        };
+
+        let marker = ListMarker::parse(&source[start..line_end])?;
+        Some((start..start + marker.len, SpanKind::ListMarker(marker.kind, depth)))
+    }
+
+    fn only_markers(text: &str) -> bool {
+        text.chars().all(|c| matches!(c, '*' | '_' | '~'))
+    }
+
+    /// Cuts one source line into cells at every span boundary, merging
+    /// neighbouring pieces that end up with the same formatting.
+    fn split_line(line: &str, line_start: usize, spans: &[(Range<usize>, SpanKind)]) -> Vec<MarkDownElements> {
+        if line.is_empty() {
+            return vec![MarkDownElements::EmptySpace];
+        }
+        let line_end = line_start + line.len();
+
+        let mut boundaries = vec![line_start, line_end];
+        for (range, _) in spans {
+            for pos in [range.start, range.end] {
+                if pos > line_start && pos < line_end && line.is_char_boundary(pos - line_start) {
+                    boundaries.push(pos);
                 }
-            }
-    
-            if !row.is_empty() {
-                result.push(row);
             }
         }
-    
-        while empty_count > 0 {
-            result.push(vec![MarkDownElements::EmptySpace]);
-            empty_count -= 1;
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        let mut cells: Vec<(CellStyle, String)> = Vec::new();
+        for window in boundaries.windows(2) {
+            let (start, end) = (window[0], window[1]);
+            let style = spans.iter()
+                .filter(|(range, _)| range.start <= start && end <= range.end)
+                .fold(CellStyle::default(), |style, (_, kind)| style.with(*kind));
+            let text = &line[start - line_start..end - line_start];
+
+            match cells.last_mut() {
+                Some((last_style, last_text)) if *last_style == style => last_text.push_str(text),
+                _ => cells.push((style, text.to_string())),
+            }
         }
-        result.push(vec![MarkDownElements::EmptySpace]);
-        result
+
+        cells.into_iter().map(|(style, text)| style.into_element(text)).collect()
     }
 
     pub fn process_text(&self, input: String) -> Vec<Vec<String>> {
@@ -252,8 +790,15 @@ impl TextProcessor {
                             HeadingLevel::H5(text) => text,
                             HeadingLevel::H6(text) => text,
                         },
+                        MarkDownElements::ListItem(item) => item.text,
+                        MarkDownElements::CodeBlock(line) => line.text,
+                        MarkDownElements::TableCell(cell) => cell.text,
                         MarkDownElements::PlainText(text) => text,
                         MarkDownElements::BoldText(text) => text,
+                        MarkDownElements::Italic(text) => text,
+                        MarkDownElements::BoldItalic(text) => text,
+                        MarkDownElements::Strikethrough(text) => text,
+                        MarkDownElements::InlineCode(text) => text,
                         MarkDownElements::EmptySpace => "".to_string()
                     })
                     .collect::<Vec<String>>()
@@ -277,6 +822,7 @@ impl TextProcessor {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_process_markdown() {
@@ -306,12 +852,300 @@ mod tests {
 
         let expected = vec![
             vec![MarkDownElements::Heading(HeadingLevel::H1("# Valid Header".to_string()))],
+            vec![MarkDownElements::EmptySpace],
             vec![MarkDownElements::PlainText("Invalid#Header".to_string())],
             vec![MarkDownElements::BoldText("**bold**".to_string())],
         ];
 
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn test_commonmark_structure() {
+        let processor = TextProcessor::new();
+        let input = "Title\n=====\n> ## Quoted\n`**not bold**` and **bold\nacross lines**";
+        let result = processor.process_markdown(input.to_string());
+
+        let expected = vec![
+            vec![MarkDownElements::Heading(HeadingLevel::H1("Title".to_string()))],
+            vec![MarkDownElements::Heading(HeadingLevel::H1("=====".to_string()))],
+            vec![
+                MarkDownElements::PlainText("> ".to_string()),
+                MarkDownElements::Heading(HeadingLevel::H2("## Quoted".to_string())),
+            ],
+            vec![
+                MarkDownElements::InlineCode("`**not bold**`".to_string()),
+                MarkDownElements::PlainText(" and ".to_string()),
+                MarkDownElements::BoldText("**bold".to_string()),
+            ],
+            vec![MarkDownElements::BoldText("across lines**".to_string())],
+        ];
+
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_inline_emphasis_elements() {
+        let processor = TextProcessor::new();
+        let input = "*it* _it_ ~~gone~~ `code` ***both*** **bold _nested_**";
+        let result = processor.process_markdown(input.to_string());
+
+        let expected = vec![vec![
+            MarkDownElements::Italic("*it*".to_string()),
+            MarkDownElements::PlainText(" ".to_string()),
+            MarkDownElements::Italic("_it_".to_string()),
+            MarkDownElements::PlainText(" ".to_string()),
+            MarkDownElements::Strikethrough("~~gone~~".to_string()),
+            MarkDownElements::PlainText(" ".to_string()),
+            MarkDownElements::InlineCode("`code`".to_string()),
+            MarkDownElements::PlainText(" ".to_string()),
+            MarkDownElements::BoldItalic("***both***".to_string()),
+            MarkDownElements::PlainText(" ".to_string()),
+            MarkDownElements::BoldText("**bold ".to_string()),
+            MarkDownElements::BoldItalic("_nested_".to_string()),
+            MarkDownElements::BoldText("**".to_string()),
+        ]];
+
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_list_items() {
+        let processor = TextProcessor::new();
+        let input = "- one **b**\n  1. nested\n- [x] done";
+        let result = processor.process_markdown(input.to_string());
+
+        let expected = vec![
+            vec![
+                MarkDownElements::ListItem(ListItem { kind: ListKind::Bullet, depth: 0, text: "- ".to_string() }),
+                MarkDownElements::PlainText("one ".to_string()),
+                MarkDownElements::BoldText("**b**".to_string()),
+            ],
+            vec![
+                MarkDownElements::ListItem(ListItem { kind: ListKind::Ordered(1), depth: 1, text: "  1. ".to_string() }),
+                MarkDownElements::PlainText("nested".to_string()),
+            ],
+            vec![
+                MarkDownElements::ListItem(ListItem { kind: ListKind::Task(true), depth: 0, text: "- [x] ".to_string() }),
+                MarkDownElements::PlainText("done".to_string()),
+            ],
+        ];
+
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_list_marker_continuation() {
+        let marker = ListMarker::parse("  9) item").unwrap();
+        assert_eq!(marker.len, 5);
+        assert_eq!(marker.next(), "  10) ");
+
+        let marker = ListMarker::parse("* [ ] todo").unwrap();
+        assert_eq!(marker.kind, ListKind::Task(false));
+        assert_eq!(marker.next(), "* [ ] ");
+
+        assert_eq!(ListMarker::parse("-not a list"), None);
+        assert_eq!(ListMarker::parse("10. item").unwrap().width, 4);
+        assert_eq!(ListMarker::parse("**bold**"), None);
+    }
+
+    #[test]
+    fn test_indent_and_outdent_lines() {
+        assert_eq!(indent_width("- item"), 2);
+        assert_eq!(indent_width("1. item"), 3);
+        assert_eq!(indent_width("- [ ] task"), 2);
+        assert_eq!(indent_width("text"), 4);
+
+        assert_eq!(outdent_line("    - item"), ("  - item".to_string(), 2));
+        assert_eq!(outdent_line("  text"), ("text".to_string(), 2));
+        assert_eq!(outdent_line("text"), ("text".to_string(), 0));
+        assert_eq!(outdent_line("\t\t- item"), ("\t- item".to_string(), 1));
+    }
+
+    #[test]
+    fn test_code_block_rows() {
+        let processor = TextProcessor::new();
+        let input = "```\n# not a heading **x**\n```\n**bold**";
+        let result = processor.process_markdown(input.to_string());
+
+        let code = |fence: bool, text: &str| MarkDownElements::CodeBlock(CodeLine {
+            language: None,
+            fence,
+            token: None,
+            text: text.to_string(),
+        });
+        let expected = vec![
+            vec![code(true, "```")],
+            vec![code(false, "# not a heading **x**")],
+            vec![code(true, "```")],
+            vec![MarkDownElements::BoldText("**bold**".to_string())],
+        ];
+
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_highlighted_code_cells() {
+        let processor = TextProcessor::new();
+        let input = "```rust\nfn main() {}\n```\n```nolang\nfn main() {}\n```";
+        let result = processor.process_markdown(input.to_string());
+
+        // Known language: the row is split into coloured cells that still join to the line
+        assert!(result[1].len() > 1);
+        assert!(result[1].iter().all(|cell| matches!(cell, MarkDownElements::CodeBlock(line) if line.token.is_some())));
+        assert_eq!(processor.extract_strings(vec![result[1].clone()])[0].concat(), "fn main() {}");
+
+        // Unknown language: one plain code cell
+        assert!(matches!(&result[4][..], [MarkDownElements::CodeBlock(line)] if line.token.is_none()));
+    }
+
+    #[test]
+    fn test_table_rows_are_split_into_columns() {
+        let processor = TextProcessor::new();
+        let input = "| a | bb |\n|---|---|\n| ccc | d |\n\n| not a table |";
+        let result = processor.process_markdown(input.to_string());
+
+        let cell = |kind: TableRowKind, column: usize, width: usize, text: &str| MarkDownElements::TableCell(TableCell {
+            kind,
+            column,
+            width,
+            text: text.to_string(),
+        });
+        assert_eq!(result[0], vec![cell(TableRowKind::Header, 0, 6, "| a "), cell(TableRowKind::Header, 1, 6, "| bb |")]);
+        assert_eq!(result[1], vec![cell(TableRowKind::Delimiter, 0, 6, "|---"), cell(TableRowKind::Delimiter, 1, 6, "|---|")]);
+        assert_eq!(result[2], vec![cell(TableRowKind::Body, 0, 6, "| ccc "), cell(TableRowKind::Body, 1, 6, "| d |")]);
+        assert_eq!(result[4], vec![MarkDownElements::PlainText("| not a table |".to_string())]);
+
+        assert_eq!(processor.table_line_ranges(input), vec![0..3]);
+        assert_eq!(
+            processor.align_tables(input),
+            "| a   | bb  |\n| --- | --- |\n| ccc | d   |\n\n| not a table |"
+        );
+    }
+
+    #[test]
+    fn test_rows_match_source_lines() {
+        let processor = TextProcessor::new();
+        let input = "a **b** c\n\n  \n# d\t\\n\r\n\tcode  \n";
+        let result = processor.process_markdown(input.to_string());
+        let text = processor.markdown_to_string(processor.extract_strings(result));
+
+        assert_eq!(text, input);
+    }
+
+    /// Source lines that exercise every kind of row: fences, tables, lists,
+    /// headings, inline styles and whitespace the parser must not touch.
+    fn markdown_lines() -> impl Strategy<Value = String> {
+        let line = prop_oneof![
+            prop::sample::select(vec![
+                "# heading", "```rust", "```", "let x = 1;\t// tab", "| a | bb |", "|---|:-:|", "|c|d",
+                "- item", "  - nested", "1. first", "> quote", "**bold** and *italic*", "~~gone~~ `code`",
+                "", "   ", "\t", "trailing  ", "literal \\n here", "crlf\r", "\r",
+            ]).prop_map(str::to_string),
+            "[ \t#*_`~|>:.a-c1-2\\-\u{1b}\u{1e}\u{1f}]{0,12}",
+        ];
+        prop::collection::vec(line, 0..24).prop_map(|lines| lines.join("\n"))
+    }
+
+    fn round_trip(source: &str) -> String {
+        let processor = TextProcessor::new();
+        processor.markdown_to_string(processor.extract_strings(processor.process_markdown(source.to_string())))
+    }
+
+    proptest! {
+        #[test]
+        fn prop_markdown_round_trips(source in markdown_lines()) {
+            prop_assert_eq!(round_trip(&source), source);
+        }
+
+        #[test]
+        fn prop_any_text_round_trips(source in "(?s).{0,200}") {
+            prop_assert_eq!(round_trip(&source), source);
+        }
+    }
+
+    /// The rows of `new` that differ from `old`, found by skipping the rows the
+    /// two have in common at the start and at the end.
+    fn changed_rows(old: &[String], new: &[String]) -> Range<usize> {
+        let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..].iter().rev()
+            .zip(new[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        prefix..new.len() - suffix
+    }
+
+    /// Parses `before`, then `after` incrementally, and checks the result
+    /// against a full parse of `after`.
+    fn assert_reparses(before: &str, after: &str) -> RowPatch {
+        let processor = TextProcessor::new();
+        let lines = |text: &str| text.split('\n').map(|line| line.to_string()).collect::<Vec<_>>();
+        let (old, new) = (lines(before), lines(after));
+
+        let mut parsed = ParsedDocument::default();
+        parsed.update(&processor, &old[..], 0..old.len());
+        let patch = parsed.update(&processor, &new[..], changed_rows(&old, &new));
+
+        assert_eq!(parsed.rows(), processor.process_markdown(after.to_string()), "{:?} -> {:?}", before, after);
+        patch
+    }
+
+    #[test]
+    fn test_reparse_only_touches_the_edited_block() {
+        let doc = "# Title\n\nsome **bold** text\n\n- a\n- b\n\nlast line";
+        let patch = assert_reparses(doc, &doc.replace("some **bold**", "some *italic*"));
+        assert_eq!(patch.new_rows(), 2..3);
+
+        // Unchanged text gives an empty patch
+        assert!(assert_reparses(doc, doc).is_empty());
+    }
+
+    #[test]
+    fn test_patches_follow_the_rows_they_move() {
+        let patch = |start, removed, added| RowPatch { start, removed, rows: vec![Vec::new(); added] };
+
+        // Rows above the patch stay, rows below move by the rows it adds
+        assert_eq!(patch(5, 1, 3).follow(None), 5..8);
+        assert_eq!(patch(5, 1, 3).follow(Some(1..2)), 1..8);
+        assert_eq!(patch(5, 1, 3).follow(Some(7..9)), 5..11);
+        // Rows it removes collapse onto where the patch starts
+        assert_eq!(patch(2, 4, 0).follow(Some(3..5)), 2..2);
+        assert_eq!(patch(2, 4, 1).follow(Some(0..10)), 0..7);
+    }
+
+    #[test]
+    fn test_reparse_follows_fences_and_lists() {
+        let doc = "intro\n\n# a\n\ncode\n\n- one\n\n- two\n\n  nested\n\nend";
+        // Opening a fence turns every following row into code, closing it turns them back
+        let opened = doc.replace("intro", "```");
+        let patch = assert_reparses(doc, &opened);
+        assert_eq!(patch.new_rows(), 0..13);
+        assert_reparses(&opened, &opened.replace("code", "```"));
+
+        // Edits inside a loose list
+        assert_reparses(doc, &doc.replace("- two", "-two"));
+        assert_reparses(doc, &doc.replace("\n\n  nested", "\n  nested"));
+
+        // Rows added and removed
+        assert_reparses(doc, &doc.replace("code\n", "code\n```\nfn\n"));
+        assert_reparses(doc, &doc.replace("# a\n\ncode\n\n", ""));
+        assert_reparses(doc, "");
+        assert_reparses("", doc);
+    }
+
+    #[test]
+    fn test_reparse_matches_blocks_across_blank_rows() {
+        // A fence in a list item closes with the item; the next fence stays open over the new row
+        assert_reparses("- a\n  ```\n```\n", "- a\n  ```\n```\n\n- [ ] t");
+        assert_reparses("- a\n  ```\n```\n\n- [ ] t", "- a\n  ```\n\n- [ ] t");
+
+        // HTML blocks and lists hold blank rows the same way
+        let doc = "<!--\nx\n-->\n\n# y\n\n- a\n\n  b\n\nend";
+        assert_reparses(doc, &doc.replace("-->", "z"));
+        assert_reparses(&doc.replace("-->", "z"), doc);
+        assert_reparses(doc, &doc.replace("  b", "b"));
+        assert_reparses(doc, &doc.replace("end", "  end"));
+    }
 }
 
 
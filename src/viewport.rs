@@ -0,0 +1,138 @@
+// src/viewport.rs
+use std::ops::Range;
+
+use crate::syntax::markdown::font_size;
+use crate::syntax::text::MarkDownElements;
+
+/// Height of a row of body text in px: the `min-height` of `.base-paragraph`.
+pub const ROW_HEIGHT: f64 = 22.0;
+
+/// Estimated height in px of a row drawn with the cells `row`: the line of its
+/// largest font (`line-height: 1.2` plus the 1px border above and below), at
+/// least `ROW_HEIGHT`. Rows that are not rendered are stood in for by spacers
+/// of this height, so a heading takes more of them than a code line. A cell
+/// whose text wraps makes the row taller than the estimate.
+pub fn row_height(row: &[MarkDownElements]) -> f64 {
+    row.iter().map(|cell| font_size(cell) as f64 * 1.2 + 2.0).fold(ROW_HEIGHT, f64::max)
+}
+
+/// The row at `y` px from the top of rows of `heights`. Rows past the last
+/// are counted at `ROW_HEIGHT`.
+fn row_at(heights: &[f64], y: f64) -> usize {
+    let mut top = 0.0;
+    for (row, height) in heights.iter().enumerate() {
+        if top + height > y {
+            return row;
+        }
+        top += height;
+    }
+    heights.len() + ((y - top) / ROW_HEIGHT) as usize
+}
+
+/// Rows rendered above and below the ones on screen, so short scrolls and
+/// caret moves land on rows that already exist.
+pub const OVERSCAN: usize = 20;
+
+/// The rows of the editor that are on screen. Only these, plus `OVERSCAN` on
+/// either side, are rendered; width measurement and the syntax pass are
+/// deferred for the rest until they scroll into view.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    pub first_row: usize,
+    pub row_count: usize,
+}
+
+impl Default for Viewport {
+    /// A guess for the first render, before the editor has been measured.
+    fn default() -> Self {
+        Self { first_row: 0, row_count: 40 }
+    }
+}
+
+impl Viewport {
+    /// The viewport of a scroll container, from its `scrollTop` and
+    /// `clientHeight` and the height of each row, see `row_height`.
+    pub fn from_scroll(scroll_top: f64, height: f64, heights: &[f64]) -> Self {
+        let scroll_top = scroll_top.max(0.0);
+        let first_row = row_at(heights, scroll_top);
+        let last_row = row_at(heights, scroll_top + height.max(0.0));
+        Self { first_row, row_count: last_row - first_row + 1 }
+    }
+
+    /// The rows to render in a document of `total` rows.
+    pub fn rendered_rows(&self, total: usize) -> Range<usize> {
+        let end = (self.first_row + self.row_count + OVERSCAN).min(total);
+        let start = self.first_row.saturating_sub(OVERSCAN).min(end);
+        start..end
+    }
+
+    /// Moves the viewport so `row` is in the middle of it, unless it is
+    /// already on screen. Used before the caret is put on a row.
+    pub fn reveal(&mut self, row: usize) {
+        if !(self.first_row..self.first_row + self.row_count).contains(&row) {
+            self.first_row = row.saturating_sub(self.row_count / 2);
+        }
+    }
+
+    /// The height of the spacer standing in for rows of `heights` that are not rendered.
+    pub fn spacer_height(heights: &[f64]) -> f64 {
+        heights.iter().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::text::HeadingLevel;
+
+    #[test]
+    fn test_rendered_rows_include_the_overscan() {
+        let heights = vec![ROW_HEIGHT; 10_000];
+        let viewport = Viewport::from_scroll(100.0 * ROW_HEIGHT, 10.0 * ROW_HEIGHT, &heights);
+        assert_eq!(viewport, Viewport { first_row: 100, row_count: 11 });
+        assert_eq!(viewport.rendered_rows(10_000), 80..131);
+
+        // Clamped to the document
+        assert_eq!(viewport.rendered_rows(105), 80..105);
+        assert_eq!(Viewport::from_scroll(0.0, 10.0 * ROW_HEIGHT, &heights[..5]).rendered_rows(5), 0..5);
+        assert_eq!(viewport.rendered_rows(0), 0..0);
+    }
+
+    #[test]
+    fn test_rows_are_sized_from_their_blocks() {
+        let text = [MarkDownElements::PlainText("text".to_string())];
+        let title = [MarkDownElements::PlainText("# ".to_string()), MarkDownElements::Heading(HeadingLevel::H1("Title".to_string()))];
+        assert_eq!(row_height(&text), ROW_HEIGHT);
+        assert_eq!(row_height(&[]), ROW_HEIGHT);
+        assert!((row_height(&title) - 40.4).abs() < 1e-9);
+
+        // A 40px heading above every nine 22px rows, 238px a section
+        let heights: Vec<f64> = (0..100).map(|row| if row % 10 == 0 { 40.0 } else { ROW_HEIGHT }).collect();
+        let viewport = Viewport::from_scroll(5.0 * 238.0, 238.0, &heights);
+        assert_eq!(viewport, Viewport { first_row: 50, row_count: 11 });
+        assert_eq!(Viewport::from_scroll(5.0 * 238.0 + 39.0, 0.0, &heights).first_row, 50);
+        assert_eq!(Viewport::from_scroll(5.0 * 238.0 + 40.0, 0.0, &heights).first_row, 51);
+
+        // The spacers are as tall as the rows they stand in for
+        let rendered = viewport.rendered_rows(heights.len());
+        assert_eq!(Viewport::spacer_height(&heights[..rendered.start]), 3.0 * 238.0);
+        assert_eq!(Viewport::spacer_height(&heights[rendered.end..]), 238.0 + 9.0 * ROW_HEIGHT);
+
+        // Past the last row, rows count at the body text height
+        assert_eq!(Viewport::from_scroll(10.0 * 238.0 + 10.0 * ROW_HEIGHT, 0.0, &heights).first_row, 110);
+    }
+
+    #[test]
+    fn test_reveal_centres_rows_off_screen() {
+        let mut viewport = Viewport { first_row: 100, row_count: 10 };
+        viewport.reveal(105);
+        assert_eq!(viewport.first_row, 100);
+
+        viewport.reveal(5_000);
+        assert_eq!(viewport.first_row, 4_995);
+        assert!(viewport.rendered_rows(10_000).contains(&5_000));
+
+        viewport.reveal(2);
+        assert_eq!(viewport.first_row, 0);
+    }
+}